@@ -1,6 +1,8 @@
 use std::ffi::{c_char, c_int, c_void};
 
-use crate::{allocator, mi_deferred_free_fun, mi_error_fun, mi_output_fun, MI_SMALL_SIZE_MAX};
+use crate::{
+    allocator, mi_arena_id_t, mi_deferred_free_fun, mi_error_fun, mi_output_fun, MI_SMALL_SIZE_MAX,
+};
 
 extern "C" {
     /// Allocate zero-initialized `size` bytes.
@@ -63,6 +65,13 @@ extern "C" {
     /// The returned size can be used to call `mi_expand` successfully.
     pub fn mi_usable_size(p: *const c_void) -> usize;
 
+    /// Like [`mi_usable_size`], as a drop-in for macOS's `malloc_size`.
+    pub fn mi_malloc_size(p: *const c_void) -> usize;
+
+    /// Like [`mi_usable_size`], as a drop-in for glibc's
+    /// `malloc_usable_size`.
+    pub fn mi_malloc_usable_size(p: *const c_void) -> usize;
+
     /// Allocate `count` items of `size` length each.
     ///
     /// Returns `null` if `count * size` overflows or on out-of-memory.
@@ -301,8 +310,8 @@ extern "C" {
     /// verifies that `p` is actually aligned to `alignment` and is usable for
     /// at least `size` bytes, before delegating to `mi_free`.
     ///
-    /// However, currently there's no way to have this crate enable mimalloc's
-    /// debug assertions, so these checks aren't particularly useful.
+    /// These assertions are only compiled in with the `debug` feature (or
+    /// `debug_in_debug` in debug builds), so they're inert otherwise.
     ///
     /// Note: It's legal to pass null to this function, and you are not required
     /// to use this to deallocate memory from an aligned allocation function.
@@ -317,8 +326,8 @@ extern "C" {
     /// verifies that `p` is actually aligned to `alignment` and is usable for
     /// at least `size` bytes, before delegating to `mi_free`.
     ///
-    /// However, currently there's no way to have this crate enable mimalloc's
-    /// debug assertions, so these checks aren't particularly useful.
+    /// These assertions are only compiled in with the `debug` feature (or
+    /// `debug_in_debug` in debug builds), so they're inert otherwise.
     ///
     /// Note: It's legal to pass null to this function.
     pub fn mi_free_size(p: *mut c_void, size: usize);
@@ -332,8 +341,8 @@ extern "C" {
     /// verifies that `p` is actually aligned to `alignment` and is usable for
     /// at least `size` bytes, before delegating to `mi_free`.
     ///
-    /// However, currently there's no way to have this crate enable mimalloc's
-    /// debug assertions, so these checks aren't particularly useful.
+    /// These assertions are only compiled in with the `debug` feature (or
+    /// `debug_in_debug` in debug builds), so they're inert otherwise.
     ///
     /// Note: It's legal to pass null to this function.
     pub fn mi_free_aligned(p: *mut c_void, alignment: usize);
@@ -429,6 +438,94 @@ extern "C" {
     /// Note: This function is thread safe.
     pub fn mi_thread_stats_print_out(out: mi_output_fun, arg: *mut c_void);
 
+    /// Reserve `pages` of huge OS pages (1GiB) evenly divided over
+    /// `numa_nodes` nodes, but stop after at most `timeout_msecs` seconds.
+    ///
+    /// Pass 0 for `numa_nodes` to use the actual detected NUMA nodes.
+    ///
+    /// The reserved memory is used by mimalloc to satisfy allocations. May
+    /// quit before `timeout_msecs` are expired if it estimates it will take
+    /// more than 1.5 times `timeout_msecs`. The time limit is needed because
+    /// on some operating systems it can take a long time to reserve contiguous
+    /// memory if the physical memory is fragmented.
+    ///
+    /// Returns 0 if successful, `ENOMEM` if running out of memory, or
+    /// `ETIMEDOUT` if timed out.
+    pub fn mi_reserve_huge_os_pages_interleave(
+        pages: usize,
+        numa_nodes: usize,
+        timeout_msecs: usize,
+    ) -> c_int;
+
+    /// Reserve `pages` of huge OS pages (1GiB) at a specific `numa_node`, but
+    /// stop after at most `timeout_msecs` seconds.
+    ///
+    /// Returns 0 if successful, `ENOMEM` if running out of memory, or
+    /// `ETIMEDOUT` if timed out.
+    ///
+    /// See [`mi_reserve_huge_os_pages_interleave`].
+    pub fn mi_reserve_huge_os_pages_at(pages: usize, numa_node: c_int, timeout_msecs: usize)
+        -> c_int;
+
+    /// Reserve OS memory for use by mimalloc.
+    ///
+    /// Reserved areas are used before allocating from the OS again. By
+    /// reserving a large area upfront, allocation can be more efficient, and
+    /// can be better managed on systems without `mmap`/`VirtualAlloc` (like
+    /// WASM for example).
+    ///
+    /// - `commit`: If true, commit the memory upfront.
+    /// - `allow_large`: Allow large OS pages (2MiB) to be used.
+    ///
+    /// Returns 0 if successful, and an error code otherwise (e.g. `ENOMEM`).
+    pub fn mi_reserve_os_memory(size: usize, commit: bool, allow_large: bool) -> c_int;
+
+    /// Reserve OS memory as a new arena, like [`mi_reserve_os_memory`].
+    ///
+    /// - `exclusive`: If true, only heaps created with
+    ///   [`mi_heap_new_in_arena`](crate::heap::mi_heap_new_in_arena) for this
+    ///   arena can allocate out of it.
+    /// - `arena_id`: Out-param receiving the id of the new arena.
+    ///
+    /// Returns 0 if successful, and an error code otherwise (e.g. `ENOMEM`).
+    pub fn mi_reserve_os_memory_ex(
+        size: usize,
+        commit: bool,
+        allow_large: bool,
+        exclusive: bool,
+        arena_id: *mut mi_arena_id_t,
+    ) -> c_int;
+
+    /// Manage a particular memory area for use by mimalloc as a new arena.
+    ///
+    /// This is just like [`mi_reserve_os_memory_ex`] except that the area
+    /// should already be allocated in some manner and available for use by
+    /// mimalloc.
+    ///
+    /// - `start`: Start of the memory area.
+    /// - `size`: The size of the memory area.
+    /// - `is_committed`: Is the area already committed?
+    /// - `is_large`: Does it consist of large OS pages? Set this to true as
+    ///   well for memory that should not be decommitted or protected (like
+    ///   rdma etc.)
+    /// - `is_zero`: Does the area consist of zero's?
+    /// - `numa_node`: Possible associated NUMA node or `-1`.
+    /// - `exclusive`: If true, only heaps created for this arena can allocate
+    ///   out of it.
+    /// - `arena_id`: Out-param receiving the id of the new arena.
+    ///
+    /// Returns `true` if successful.
+    pub fn mi_manage_os_memory_ex(
+        start: *mut c_void,
+        size: usize,
+        is_committed: bool,
+        is_large: bool,
+        is_zero: bool,
+        numa_node: c_int,
+        exclusive: bool,
+        arena_id: *mut mi_arena_id_t,
+    ) -> bool;
+
     /// Register an output function.
     ///
     /// - `out` The output function, use `None` to output to stderr.
@@ -1,6 +1,6 @@
 use std::ffi::{c_char, c_void};
 
-use crate::{allocator, mi_block_visit_fun};
+use crate::{allocator, mi_arena_id_t, mi_block_visit_fun};
 
 /// First-class heaps that can be destroyed in one go.
 ///
@@ -41,7 +41,7 @@ pub struct mi_heap_area_t {
     pub reserved: usize,
     /// Current committed bytes of this area.
     pub committed: usize,
-    /// Bytes in use by allocated blocks.
+    /// Number of allocated blocks (not bytes).
     pub used: usize,
     /// Size in bytes of one block.
     pub block_size: usize,
@@ -49,10 +49,26 @@ pub struct mi_heap_area_t {
     pub full_block_size: usize,
 }
 
+// mimalloc 2.1.2 declares six pointer-sized fields, in this order. Later
+// versions append fields (e.g. `heap_tag`), so catch a mismatch at compile time
+// instead of reading garbage in block visitors.
+const _: () = {
+    use core::mem::{offset_of, size_of};
+
+    assert!(size_of::<mi_heap_area_t>() == 6 * size_of::<usize>());
+    assert!(offset_of!(mi_heap_area_t, used) == 3 * size_of::<usize>());
+    assert!(offset_of!(mi_heap_area_t, full_block_size) == 5 * size_of::<usize>());
+};
+
 extern "C" {
     /// Create a new heap that can be used for allocation.
     pub fn mi_heap_new() -> *mut mi_heap_t;
 
+    /// Create a new heap that only allocates in the specified arena.
+    ///
+    /// Returns null if the heap could not be created.
+    pub fn mi_heap_new_in_arena(arena_id: mi_arena_id_t) -> *mut mi_heap_t;
+
     /// Delete a previously allocated heap.
     ///
     /// This will release resources and migrate any still allocated blocks in
@@ -114,6 +130,12 @@ extern "C" {
     /// `size` must be smaller or equal to [`MI_SMALL_SIZE_MAX`](crate::MI_SMALL_SIZE_MAX).
     pub fn mi_heap_malloc_small(heap: *mut mi_heap_t, size: usize) -> *mut c_void;
 
+    // There's no binding for guarded allocation (guard pages after sampled
+    // blocks): mimalloc 2.1.2 doesn't support it, and later versions only
+    // enable it through the `MI_GUARDED` build define and the `guarded_*`
+    // options rather than an exported `mi_heap_malloc_guarded`. Add it with
+    // those options when the vendored mimalloc is upgraded.
+
     /// Equivalent to [`mi_realloc`](allocator::mi_realloc), but allocates out of
     /// the specific heap instead of the default.
     pub fn mi_heap_realloc(heap: *mut mi_heap_t, p: *mut c_void, newsize: usize) -> *mut c_void;
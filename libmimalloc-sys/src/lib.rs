@@ -38,6 +38,11 @@ Option<unsafe extern "C" fn(force: bool, heartbeat: c_ulonglong, arg: *mut c_voi
 /// See [`mi_register_error`](allocator::mi_register_error)
 pub type mi_error_fun = Option<unsafe extern "C" fn(code: c_int, arg: *mut c_void)>;
 
+/// Identifies an arena of OS memory managed by mimalloc.
+///
+/// See [`mi_reserve_os_memory_ex`](allocator::mi_reserve_os_memory_ex), [`mi_manage_os_memory_ex`](allocator::mi_manage_os_memory_ex), [`mi_heap_new_in_arena`](heap::mi_heap_new_in_arena)
+pub type mi_arena_id_t = c_int;
+
 /// Runtime options. All options are false by default.
 pub type mi_option_t = c_int;
 
@@ -94,6 +99,10 @@ pub const mi_option_reserve_os_memory: mi_option_t = 9;
 /// Option (experimental) the first N segments per thread are not eagerly committed (=1).
 pub const mi_option_eager_commit_delay: mi_option_t = 14;
 
+/// Option (experimental) Delay in milli-seconds before purging (returning to the OS) freed memory,
+/// e.g. on collection without `force`.
+pub const mi_option_purge_delay: mi_option_t = 15;
+
 /// Option (experimental) Pretend there are at most N NUMA nodes; Use 0 to use the actual detected NUMA nodes at runtime.
 pub const mi_option_use_numa_nodes: mi_option_t = 16;
 
@@ -135,6 +144,7 @@ pub type mi_block_visit_fun = Option<
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::options::{mi_option_get, mi_option_set};
 
     #[test]
     fn runtime_stable_option() {
@@ -152,4 +162,12 @@ mod tests {
             assert_eq!(mi_option_get(mi_option_verbose), 1);
         }
     }
+
+    #[test]
+    fn heap_area_layout_matches_vendored_version() {
+        // the layout of `mi_heap_area_t` is only known to match 2.1.2, update
+        // it along with the vendored sources
+        assert_eq!(unsafe { allocator::mi_version() }, 212);
+        assert_eq!(core::mem::size_of::<mi_heap_area_t>(), 6 * core::mem::size_of::<usize>());
+    }
 }
\ No newline at end of file
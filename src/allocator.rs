@@ -1,24 +1,127 @@
-use std::ffi::{
-    c_char,
-    c_void,
+use std::{
+    alloc::{
+        handle_alloc_error,
+        Layout,
+    },
+    collections::BTreeMap,
+    ffi::{
+        c_char,
+        c_void,
+        CStr,
+    },
+    fmt,
+    hash::{
+        Hash,
+        Hasher,
+    },
+    io,
+    marker::PhantomData,
+    mem::{
+        align_of,
+        size_of,
+        ManuallyDrop,
+        MaybeUninit,
+    },
+    ptr::NonNull,
+    sync::{
+        atomic::{
+            AtomicPtr,
+            Ordering,
+        },
+        Arc,
+    },
+    thread::{
+        self,
+        ThreadId,
+    },
 };
 
 use cesium_libmimalloc_sys as mi;
-use cesium_libmimalloc_sys::allocator::mi_free;
+use cesium_libmimalloc_sys::allocator::{
+    mi_expand,
+    mi_free,
+    mi_free_size_aligned,
+    mi_good_size,
+    mi_is_in_heap_region,
+    mi_posix_memalign,
+};
 use mi::{
     heap::*,
     mi_block_visit_fun,
 };
 
+use crate::{
+    area::{
+        AreaInfo,
+        BlockRef,
+        HeapUsage,
+    },
+    boxed::HeapBox,
+    cstr::HeapCStr,
+    error::AllocError,
+    hooks::{
+        register_deferred_free,
+        unregister_deferred_free,
+    },
+    slice::{
+        HeapSlice,
+        Zeroable,
+    },
+};
+
 /// A general-purpose memory allocator. It's recommended to use the Allocator
 /// Pool to manage allocator instances. It is important to be aware that `*mut
 /// u8` return types are type-friendly wrappers on top of
 /// [`libc::c_void`](libc::c_void), which is just a `void*` in C.
+///
+/// A heap belongs to the thread that created it. Blocks can be freed from any
+/// thread, but allocating out of a heap or inspecting it can only happen on
+/// its own thread: the allocating methods, the methods walking the heap (such
+/// as [`visit_blocks`](Allocator::visit_blocks) and
+/// [`live_bytes`](Allocator::live_bytes)), [`collect`](Allocator::collect) and
+/// [`as_default`](Allocator::as_default) panic on any other thread.
+///
+/// An `Allocator` owns its heap, so it isn't `Clone`: share it through the
+/// `Arc` handed out by the pool, or create a separate heap with
+/// [`AllocatorPool::duplicate_heap`](crate::AllocatorPool::duplicate_heap).
+///
+/// ```compile_fail
+/// use cesium_allocator::allocator::Allocator;
+///
+/// fn assert_clone<T: Clone>() {}
+/// assert_clone::<Allocator>();
+/// ```
 pub struct Allocator {
     id: u32,
-    heap: *mut mi_heap_t,
+    heap: AtomicPtr<mi_heap_t>,
+    owned: bool,
+    owner: ThreadId,
+    policy: AllocPolicy,
+    name: Option<String>,
+}
+
+/// What an [`Allocator`] does when an allocation fails.
+///
+/// The policy applies to the methods returning raw pointers, such as
+/// [`malloc`](Allocator::malloc) and [`realloc`](Allocator::realloc), and the
+/// typed helpers built on them. The `try_` methods are always fallible.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AllocPolicy {
+    /// Return null, like mimalloc itself.
+    #[default]
+    ReturnNull,
+    /// Call [`handle_alloc_error`], which aborts the process by default.
+    Abort,
 }
 
+// Safety: freeing is thread-safe in mimalloc, and heap deletion is guarded by
+// the owning thread in `Drop`. Every method dereferencing the heap, whether to
+// allocate from it, collect it or walk its pages, goes through `local_heap`,
+// which panics on any other thread. The remaining uses of `heap` only compare
+// the pointer.
+unsafe impl Send for Allocator {}
+unsafe impl Sync for Allocator {}
+
 impl Default for Allocator {
     /// Create an allocator that uses the default heap.
     ///
@@ -27,25 +130,372 @@ impl Default for Allocator {
     fn default() -> Self {
         Allocator {
             id: 0,
-            heap: unsafe { mi_heap_get_default() },
+            heap: AtomicPtr::new(unsafe { mi_heap_get_default() }),
+            owned: false,
+            owner: thread::current().id(),
+            policy: AllocPolicy::ReturnNull,
+            name: None,
+        }
+    }
+}
+
+impl fmt::Debug for Allocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the heap is opaque, so only its address is printed
+        f.debug_struct("Allocator")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("heap", &self.heap())
+            .field("owned", &self.owned)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Allocators are equal if they have the same [`id`](Allocator::id), whatever
+/// their heaps, so they can be used as keys by pool id.
+impl PartialEq for Allocator {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Allocator {}
+
+impl Hash for Allocator {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+/// Restores the previous default heap of the thread when dropped.
+///
+/// See [`Allocator::as_default`].
+pub struct DefaultHeapGuard<'a> {
+    previous: *mut mi_heap_t,
+    _alloc: PhantomData<&'a Allocator>,
+}
+
+impl Drop for DefaultHeapGuard<'_> {
+    fn drop(&mut self) {
+        unsafe { mi_heap_set_default(self.previous) };
+    }
+}
+
+impl Drop for Allocator {
+    /// Delete the underlying heap if this allocator owns it.
+    ///
+    /// Blocks still allocated in the heap are migrated to the default heap, so
+    /// they remain valid and can still be freed.
+    ///
+    /// Heaps can only be deleted by the thread that created them. If the last
+    /// reference is dropped on another thread the heap is left alone, and
+    /// mimalloc deletes it when its thread exits.
+    fn drop(&mut self) {
+        if self.owned && self.owner == thread::current().id() {
+            unsafe { mi_heap_delete(self.heap()) };
         }
     }
 }
 
 impl Allocator {
+    /// Create an allocator over `heap`, taking ownership of it.
+    ///
+    /// The heap is deleted with [`mi_heap_delete`] when the allocator is
+    /// dropped, so `heap` must have come from [`mi_heap_new`] and must not be
+    /// the default or backing heap.
     pub fn new(id: u32, heap: *mut mi_heap_t) -> Self {
-        Allocator { id, heap }
+        Allocator {
+            id,
+            heap: AtomicPtr::new(heap),
+            owned: true,
+            owner: thread::current().id(),
+            policy: AllocPolicy::ReturnNull,
+            name: None,
+        }
+    }
+
+    /// Create an allocator over the thread's backing heap.
+    ///
+    /// The backing heap can't be deleted or destroyed, so it's always
+    /// available, e.g. as a fallback when a custom heap is destroyed. Like
+    /// [`Allocator::default`] the allocator doesn't own the heap, so dropping
+    /// it leaves the heap alone.
+    pub fn backing() -> Self {
+        Allocator {
+            id: 0,
+            heap: AtomicPtr::new(unsafe { mi_heap_get_backing() }),
+            owned: false,
+            owner: thread::current().id(),
+            policy: AllocPolicy::ReturnNull,
+            name: None,
+        }
+    }
+
+    /// Give up the heap without deleting it, e.g. to hand it to C code as an
+    /// opaque handle.
+    ///
+    /// Rebuild the allocator with [`from_raw`](Allocator::from_raw), or the
+    /// heap is only deleted when its thread exits.
+    pub fn into_raw(self) -> *mut mi_heap_t {
+        ManuallyDrop::new(self).heap()
+    }
+
+    /// Rebuild an allocator from a heap given up by
+    /// [`into_raw`](Allocator::into_raw), taking ownership of it again.
+    ///
+    /// # Safety
+    ///
+    /// `heap` must have come from [`mi_heap_new`], must not be owned by
+    /// another allocator and must not have been deleted or destroyed.
+    pub unsafe fn from_raw(id: u32, heap: *mut mi_heap_t) -> Self {
+        Allocator::new(id, heap)
+    }
+
+    /// Set what happens when an allocation fails, see [`AllocPolicy`].
+    pub fn with_policy(mut self, policy: AllocPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// What happens when an allocation fails.
+    pub fn policy(&self) -> AllocPolicy {
+        self.policy
     }
 
-    pub fn id(self) -> u32 {
+    /// The id of this allocator in its pool.
+    pub fn id(&self) -> u32 {
         self.id
     }
 
+    /// Tag the allocator with `name`, e.g. the subsystem using it, to tell
+    /// heaps apart in logs. The name is only used for diagnostics and shows up
+    /// in the `Debug` output.
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// The name set with [`with_name`](Allocator::with_name), if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The thread the allocator was created on, the only one that may
+    /// allocate from its heap.
+    pub fn owner(&self) -> ThreadId {
+        self.owner
+    }
+
+    /// Make this allocator's heap the thread's default heap, used by
+    /// [`mi_malloc`](mi::allocator::mi_malloc) et al, until the returned guard
+    /// is dropped.
+    ///
+    /// The previous default heap is restored when the guard drops. The guard
+    /// can't be sent to another thread, as the default heap is per thread.
+    pub fn as_default(&self) -> DefaultHeapGuard<'_> {
+        DefaultHeapGuard {
+            previous: unsafe { mi_heap_set_default(self.local_heap()) },
+            _alloc: PhantomData,
+        }
+    }
+
+    /// Destroy the heap, freeing every block still allocated in it at once.
+    ///
+    /// This is much faster than freeing each block, e.g. to drop everything
+    /// allocated while handling a request.
+    ///
+    /// # Safety
+    ///
+    /// **Every pointer allocated from this heap becomes dangling**, including
+    /// ones held by [`HeapBox`], [`HeapSlice`] or [`HeapCStr`] values and
+    /// blocks that were handed to other threads. None of them may be used or
+    /// freed afterwards.
+    ///
+    /// # Panics
+    ///
+    /// If the allocator doesn't own its heap (e.g. [`Allocator::default`]), or
+    /// isn't on the thread that created the heap.
+    pub unsafe fn destroy(self) {
+        assert!(self.owned, "only an owned heap can be destroyed");
+        assert_eq!(
+            self.owner,
+            thread::current().id(),
+            "a heap can only be destroyed by its own thread"
+        );
+
+        let this = ManuallyDrop::new(self);
+        mi_heap_destroy(this.heap());
+    }
+
+    /// Stop using the heap and delete it, without invalidating the blocks
+    /// still allocated in it.
+    ///
+    /// Unlike [`destroy`](Allocator::destroy), the heap is deleted with
+    /// [`mi_heap_delete`]: its blocks migrate to the default heap, and stay
+    /// valid until they're freed as usual (e.g. with `mi_free`).
+    ///
+    /// # Panics
+    ///
+    /// If the allocator doesn't own its heap (e.g. [`Allocator::default`]), or
+    /// isn't on the thread that created the heap.
+    pub fn abandon(self) {
+        assert!(self.owned, "only an owned heap can be abandoned");
+        assert_eq!(
+            self.owner,
+            thread::current().id(),
+            "a heap can only be abandoned by its own thread"
+        );
+
+        let this = ManuallyDrop::new(self);
+        unsafe { mi_heap_delete(this.heap()) };
+    }
+
+    /// Swap the heap for a fresh one, e.g. to reuse a request-scoped allocator
+    /// for the next request.
+    ///
+    /// The old heap is deleted with [`mi_heap_delete`] rather than destroyed:
+    /// blocks still allocated in it are migrated to the default heap, so
+    /// pointers handed out before the reset stay valid and must still be
+    /// freed, but the allocator's usage starts from zero again. This keeps
+    /// `reset` safe; use [`destroy`](Allocator::destroy) to free every block
+    /// at once instead.
+    ///
+    /// # Panics
+    ///
+    /// If the allocator doesn't own its heap (e.g. [`Allocator::default`]), or
+    /// isn't on the thread that created the heap.
+    pub fn reset(&self) {
+        assert!(self.owned, "only an owned heap can be reset");
+        assert_eq!(
+            self.owner,
+            thread::current().id(),
+            "a heap can only be reset by its own thread"
+        );
+
+        let old = self.heap.swap(unsafe { mi_heap_new() }, Ordering::AcqRel);
+        unsafe { mi_heap_delete(old) };
+    }
+
+    /// The underlying mimalloc heap.
+    ///
+    /// mimalloc heaps are thread-local, so the heap should only be
+    /// dereferenced on the [`owner`](Allocator::owner) thread.
+    pub fn heap(&self) -> *mut mi_heap_t {
+        self.heap.load(Ordering::Acquire)
+    }
+
+    /// The underlying heap, for allocating from it, walking it or otherwise
+    /// dereferencing it.
+    ///
+    /// mimalloc heaps are thread-local, so this panics if called on another
+    /// thread than the owner.
+    fn local_heap(&self) -> *mut mi_heap_t {
+        assert_eq!(
+            self.owner,
+            thread::current().id(),
+            "a heap can only be used by its own thread"
+        );
+        self.heap()
+    }
+
+    /// Whether `heap` is the underlying mimalloc heap, e.g. to check that a
+    /// heap handle which went through C code belongs to this allocator.
+    ///
+    /// Only the pointers are compared; `heap` isn't dereferenced.
+    pub fn is_same_heap(&self, heap: *const mi_heap_t) -> bool {
+        std::ptr::eq(self.heap(), heap)
+    }
+
     /// Release outstanding resources in a specific heap.
+    ///
+    /// See [`collect_light`](Allocator::collect_light) (`force` false) and
+    /// [`collect_force`](Allocator::collect_force) (`force` true).
     pub fn collect(&self, force: bool) {
         unsafe {
-            mi_heap_collect(self.heap, force);
+            mi_heap_collect(self.local_heap(), force);
+        }
+    }
+
+    /// Collect the heap without forcing, which is cheap enough to call often.
+    ///
+    /// Freed pages go back to their segments, but the memory is only returned
+    /// to the OS once its purge delay (`purge_delay`, 10ms by default) has
+    /// expired.
+    pub fn collect_light(&self) {
+        self.collect(false)
+    }
+
+    /// Collect the heap and return all the free memory to the OS right away,
+    /// ignoring the purge delay.
+    ///
+    /// This decommits memory, so allocating it again is slower; prefer
+    /// [`collect_light`](Allocator::collect_light) on hot paths.
+    pub fn collect_force(&self) {
+        self.collect(true)
+    }
+
+    /// Collect the heap every `heartbeat_interval` heartbeats, returning memory
+    /// during quiet periods.
+    ///
+    /// This is built on [`register_deferred_free`]: mimalloc's heartbeat ticks
+    /// on the slow allocation path of each thread, and the heap is collected
+    /// whenever its owner thread's heartbeat is a multiple of
+    /// `heartbeat_interval`, or when mimalloc asks to free everything. An
+    /// interval of 0 is treated as 1.
+    ///
+    /// The deferred free hook is process-wide and single-slot, so only one
+    /// allocator can collect on idle at a time: this replaces any earlier
+    /// deferred free function, including another allocator's idle collection.
+    /// The allocator is only referenced weakly, so it can still be dropped.
+    /// Stop with [`disable_idle_collection`](Allocator::disable_idle_collection).
+    pub fn enable_idle_collection(self: &Arc<Self>, heartbeat_interval: u64) {
+        let alloc = Arc::downgrade(self);
+        let owner = self.owner;
+        let interval = heartbeat_interval.max(1);
+
+        register_deferred_free(move |force, heartbeat| {
+            if thread::current().id() != owner || !(force || heartbeat % interval == 0) {
+                return;
+            }
+
+            if let Some(alloc) = alloc.upgrade() {
+                alloc.collect(force);
+                // the heap may be in use by the allocation that triggered this
+                // hook, so don't delete it here if this was the last reference;
+                // mimalloc deletes it when the thread exits
+                if let Some(last) = Arc::into_inner(alloc) {
+                    std::mem::forget(last);
+                }
+            }
+        });
+    }
+
+    /// Stop collecting on idle, see
+    /// [`enable_idle_collection`](Allocator::enable_idle_collection).
+    ///
+    /// This removes whatever deferred free function is registered.
+    pub fn disable_idle_collection() {
+        unregister_deferred_free();
+    }
+
+    /// Commit `bytes` of memory up front, so the first requests after startup
+    /// don't pay for page faults.
+    ///
+    /// Allocates `bytes`, writes a byte to every page to force it to be
+    /// committed, then frees the block again, leaving it cached in the heap.
+    /// Does nothing on out-of-memory, regardless of the [`AllocPolicy`].
+    pub fn warmup(&self, bytes: usize) {
+        const PAGE: usize = 4096;
+
+        let p = unsafe { mi_heap_malloc(self.local_heap(), bytes) } as *mut u8;
+        if p.is_null() {
+            return;
+        }
+
+        for offset in (0..bytes).step_by(PAGE) {
+            unsafe { p.add(offset).write_volatile(0) };
         }
+        self.free(p);
     }
 
     /// Allocate `size` bytes.
@@ -53,19 +503,38 @@ impl Allocator {
     /// Returns pointer to the allocated memory or null if out of memory.
     /// Returns a unique pointer if called with `size` 0.
     pub fn malloc(&self, size: usize) -> *mut u8 {
-        unsafe { mi_heap_malloc(self.heap, size) as *mut u8 }
+        self.check(unsafe { mi_heap_malloc(self.local_heap(), size) }, size, 1)
     }
 
     pub fn free(&self, p: *mut u8) {
         unsafe { mi_free(p as *mut c_void) }
     }
 
+    /// Free `p`, which was allocated with `layout`.
+    ///
+    /// The same as [`free`](Allocator::free), except that with the
+    /// `debug-full` feature mimalloc asserts that the block fits `layout`.
+    pub fn free_sized(&self, p: *mut u8, layout: Layout) {
+        unsafe { mi_free_size_aligned(p as *mut c_void, layout.size(), layout.align()) }
+    }
+
+    /// Allocate `size` bytes, meant for sizes where [`is_huge`] is true.
+    ///
+    /// This is the same as [`malloc`](Allocator::malloc), and only names the
+    /// intent: mimalloc serves huge blocks from a dedicated segment mapped
+    /// directly from the OS (or a reserved arena), and returns that memory
+    /// eagerly when the block is freed instead of keeping it in the heap.
+    /// Smaller sizes simply take the regular path.
+    pub fn malloc_huge(&self, size: usize) -> *mut u8 {
+        self.malloc(size)
+    }
+
     /// Allocate zero-initialized `size` bytes.
     ///
     /// Returns a pointer to newly allocated zero-initialized memory, or null if
     /// out of memory.
     pub fn zalloc(&self, size: usize) -> *mut u8 {
-        unsafe { mi_heap_zalloc(self.heap, size) as *mut u8 }
+        self.check(unsafe { mi_heap_zalloc(self.local_heap(), size) }, size, 1)
     }
 
     /// Allocate `count` items of `size` length each.
@@ -74,7 +543,8 @@ impl Allocator {
     ///
     /// All items are initialized to zero.
     pub fn calloc(&self, count: usize, size: usize) -> *mut u8 {
-        unsafe { mi_heap_calloc(self.heap, count, size) as *mut u8 }
+        let p = unsafe { mi_heap_calloc(self.local_heap(), count, size) };
+        self.check(p, count.saturating_mul(size), 1)
     }
 
     /// Allocate `count` items of `size` length each.
@@ -85,7 +555,105 @@ impl Allocator {
     /// [`calloc`](Allocator::calloc), but returns uninitialized (and not
     /// zeroed) bytes.
     pub fn mallocn(&self, count: usize, size: usize) -> *mut u8 {
-        unsafe { mi_heap_mallocn(self.heap, count, size) as *mut u8 }
+        let p = unsafe { mi_heap_mallocn(self.local_heap(), count, size) };
+        self.check(p, count.saturating_mul(size), 1)
+    }
+
+    /// Allocate `count` separate blocks of `size` bytes each, e.g. to fill an
+    /// object pool.
+    ///
+    /// This is all-or-nothing: if any allocation fails, the blocks already
+    /// allocated are freed and an empty `Vec` is returned. That includes the
+    /// `Vec` itself failing to allocate room for `count` pointers.
+    pub fn malloc_batch(&self, count: usize, size: usize) -> Vec<*mut u8> {
+        let mut blocks = Vec::new();
+        if blocks.try_reserve_exact(count).is_err() {
+            return Vec::new();
+        }
+
+        for _ in 0..count {
+            let p = unsafe { mi_heap_malloc(self.local_heap(), size) };
+            if p.is_null() {
+                for p in blocks.drain(..) {
+                    self.free(p);
+                }
+                self.check(p, size, 1);
+                break;
+            }
+            blocks.push(p as *mut u8);
+        }
+        blocks
+    }
+
+    /// Checked [`malloc`](Allocator::malloc): returns `None` on out-of-memory.
+    ///
+    /// Like every `try_` method this is fallible regardless of the
+    /// [`AllocPolicy`].
+    pub fn try_malloc(&self, size: usize) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe { mi_heap_malloc(self.local_heap(), size) } as *mut u8)
+    }
+
+    /// Checked [`zalloc`](Allocator::zalloc): returns `None` on out-of-memory.
+    pub fn try_zalloc(&self, size: usize) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe { mi_heap_zalloc(self.local_heap(), size) } as *mut u8)
+    }
+
+    /// Checked [`calloc`](Allocator::calloc).
+    ///
+    /// Returns [`AllocError::Overflow`] if `count * size` overflows, checked
+    /// before calling into mimalloc, or [`AllocError::OutOfMemory`] on
+    /// out-of-memory.
+    pub fn try_calloc(&self, count: usize, size: usize) -> Result<NonNull<u8>, AllocError> {
+        count.checked_mul(size).ok_or(AllocError::Overflow)?;
+        let p = unsafe { mi_heap_calloc(self.local_heap(), count, size) } as *mut u8;
+        NonNull::new(p).ok_or(AllocError::OutOfMemory)
+    }
+
+    /// Checked [`mallocn`](Allocator::mallocn).
+    ///
+    /// Returns [`AllocError::Overflow`] if `count * size` overflows, checked
+    /// before calling into mimalloc, or [`AllocError::OutOfMemory`] on
+    /// out-of-memory.
+    pub fn try_mallocn(&self, count: usize, size: usize) -> Result<NonNull<u8>, AllocError> {
+        count.checked_mul(size).ok_or(AllocError::Overflow)?;
+        let p = unsafe { mi_heap_mallocn(self.local_heap(), count, size) } as *mut u8;
+        NonNull::new(p).ok_or(AllocError::OutOfMemory)
+    }
+
+    /// Checked [`malloc_aligned`](Allocator::malloc_aligned).
+    ///
+    /// Returns [`AllocError::InvalidAlignment`] if `alignment` isn't a power of
+    /// two, checked before calling into mimalloc, or
+    /// [`AllocError::OutOfMemory`] on out-of-memory.
+    pub fn try_malloc_aligned(
+        &self,
+        size: usize,
+        alignment: usize,
+    ) -> Result<NonNull<u8>, AllocError> {
+        if !alignment.is_power_of_two() {
+            return Err(AllocError::InvalidAlignment);
+        }
+
+        let p = unsafe { mi_heap_malloc_aligned(self.local_heap(), size, alignment) } as *mut u8;
+        NonNull::new(p).ok_or(AllocError::OutOfMemory)
+    }
+
+    /// Checked [`malloc_aligned_at`](Allocator::malloc_aligned_at).
+    ///
+    /// Fails the same way as
+    /// [`try_malloc_aligned`](Allocator::try_malloc_aligned).
+    pub fn try_malloc_aligned_at(
+        &self,
+        size: usize,
+        alignment: usize,
+        offset: usize,
+    ) -> Result<NonNull<u8>, AllocError> {
+        if !alignment.is_power_of_two() {
+            return Err(AllocError::InvalidAlignment);
+        }
+
+        let p = unsafe { mi_heap_malloc_aligned_at(self.local_heap(), size, alignment, offset) };
+        NonNull::new(p as *mut u8).ok_or(AllocError::OutOfMemory)
     }
 
     /// Allocate an object of no more than [`SMALL_SIZE_MAX`](MI_SMALL_SIZE_MAX)
@@ -97,7 +665,11 @@ impl Allocator {
     /// `size` is small and calls this if
     /// so at runtime, so its' only worth using if you know for certain.
     pub fn malloc_small(&self, size: usize) -> *mut u8 {
-        unsafe { mi_heap_malloc_small(self.heap, size) as *mut u8 }
+        self.check(
+            unsafe { mi_heap_malloc_small(self.local_heap(), size) },
+            size,
+            1,
+        )
     }
 
     /// Zero initialized re-allocation.
@@ -107,7 +679,11 @@ impl Allocator {
     /// [`zalloc`](Allocator::zalloc),
     /// [`zalloc_aligned`](Allocator::zalloc_aligned), ...
     pub fn realloc(&self, p: *mut u8, newsize: usize) -> *mut u8 {
-        unsafe { mi_heap_realloc(self.heap, p as *mut c_void, newsize) as *mut u8 }
+        self.check(
+            unsafe { mi_heap_realloc(self.local_heap(), p as *mut c_void, newsize) },
+            newsize,
+            1,
+        )
     }
 
     /// Re-allocate memory to `count` elements of `size` bytes.
@@ -117,7 +693,8 @@ impl Allocator {
     /// otherwise returns the same as [`realloc(p, count *
     /// size)`](Allocator::realloc).
     pub fn reallocn(&self, p: *mut u8, count: usize, size: usize) -> *mut u8 {
-        unsafe { mi_heap_reallocn(self.heap, p as *mut c_void, count, size) as *mut u8 }
+        let q = unsafe { mi_heap_reallocn(self.local_heap(), p as *mut c_void, count, size) };
+        self.check(q, count.saturating_mul(size), 1)
     }
 
     /// Re-allocate memory to `newsize` bytes.
@@ -125,20 +702,69 @@ impl Allocator {
     /// This differs from [`realloc`](Allocator::realloc) in that on failure,
     /// `p` is freed.
     pub fn reallocf(&self, p: *mut u8, newsize: usize) -> *mut u8 {
-        unsafe { mi_heap_reallocf(self.heap, p as *mut c_void, newsize) as *mut u8 }
+        self.check(
+            unsafe { mi_heap_reallocf(self.local_heap(), p as *mut c_void, newsize) },
+            newsize,
+            1,
+        )
+    }
+
+    /// Try to re-allocate memory to `newsize` bytes _in place_.
+    ///
+    /// Returns null if the block could not be expanded in place, in which case
+    /// `p` is untouched and still valid. On success, returns `p` itself. Bytes
+    /// past the original size are uninitialized.
+    ///
+    /// There is no heap-specific variant of `mi_expand`, but as it only works
+    /// on the block itself the heap doesn't matter.
+    pub fn expand(&self, p: *mut u8, newsize: usize) -> *mut u8 {
+        unsafe { mi_expand(p as *mut c_void, newsize) as *mut u8 }
+    }
+
+    /// Checked [`expand`](Allocator::expand): returns `None` if the block
+    /// could not be expanded in place, leaving `p` untouched.
+    pub fn try_expand(&self, p: *mut u8, newsize: usize) -> Option<NonNull<u8>> {
+        NonNull::new(self.expand(p, newsize))
+    }
+
+    /// Grow the block at `p` to `newsize` bytes if and only if it fits in
+    /// place, never moving or copying it.
+    ///
+    /// Returns whether the block was grown; either way `p` stays valid and
+    /// its contents untouched.
+    pub fn grow_in_place(&self, p: *mut u8, newsize: usize) -> bool {
+        !self.expand(p, newsize).is_null()
+    }
+
+    /// Shrink the `old` byte block at `p` to `new` bytes, keeping the pointer
+    /// whenever the block is large enough.
+    ///
+    /// [`realloc`](Allocator::realloc) moves a block shrunk below half its
+    /// size to reclaim the memory; this keeps `p` as long as `new` fits in its
+    /// usable size, trading the memory for pointer stability. Otherwise it
+    /// falls back to `realloc`, returning null on out-of-memory with `p` left
+    /// untouched.
+    pub fn shrink(&self, p: *mut u8, old: usize, new: usize) -> *mut u8 {
+        let usable = unsafe { mi::allocator::mi_usable_size(p as *const c_void) };
+        debug_assert!(p.is_null() || usable >= old);
+
+        if !p.is_null() && new <= usable {
+            return p;
+        }
+        self.realloc(p, new)
     }
 
     /// Allocate and duplicate a nul-terminated C string. Because this could be
     /// either an i8 or u8, the original type is left unwrapped.
     pub fn strdup(&self, s: *const c_char) -> *mut c_char {
-        unsafe { mi_heap_strdup(self.heap, s) }
+        unsafe { mi_heap_strdup(self.local_heap(), s) }
     }
 
     /// Allocate and duplicate a nul-terminated C string, up to `n` bytes.
     /// Because this could be either an i8 or u8, the original type is left
     /// unwrapped.
     pub fn strndup(&self, s: *const c_char, n: usize) -> *mut c_char {
-        unsafe { mi_heap_strndup(self.heap, s, n) }
+        unsafe { mi_heap_strndup(self.local_heap(), s, n) }
     }
 
     /// Resolve a file path name, producing a `C` string which can be passed to
@@ -156,7 +782,37 @@ impl Allocator {
     /// This can rarely be useful in FFI code, but is mostly included for
     /// completeness.
     pub fn realpath(&self, fname: *const c_char, resolved_name: *mut c_char) -> *mut c_char {
-        unsafe { mi_heap_realpath(self.heap, fname, resolved_name) }
+        unsafe { mi_heap_realpath(self.local_heap(), fname, resolved_name) }
+    }
+
+    /// Duplicate `s` into this heap.
+    ///
+    /// The safe counterpart of [`strdup`](Allocator::strdup). Returns `None` on
+    /// out-of-memory.
+    pub fn dup_cstr(&self, s: &CStr) -> Option<HeapCStr<'_>> {
+        let p = NonNull::new(self.strdup(s.as_ptr()))?;
+        Some(unsafe { HeapCStr::from_raw(self, p) })
+    }
+
+    /// Duplicate at most the first `n` bytes of `s` into this heap.
+    ///
+    /// The safe counterpart of [`strndup`](Allocator::strndup); the copy is
+    /// always nul-terminated. Returns `None` on out-of-memory.
+    pub fn dup_cstr_n(&self, s: &CStr, n: usize) -> Option<HeapCStr<'_>> {
+        let p = NonNull::new(self.strndup(s.as_ptr(), n))?;
+        Some(unsafe { HeapCStr::from_raw(self, p) })
+    }
+
+    /// Resolve `path` to an absolute file name allocated in this heap.
+    ///
+    /// The safe counterpart of [`realpath`](Allocator::realpath). Returns the
+    /// OS error (from `errno`) if the path can't be resolved.
+    pub fn resolve_path(&self, path: &CStr) -> io::Result<HeapCStr<'_>> {
+        let p = self.realpath(path.as_ptr(), std::ptr::null_mut());
+        match NonNull::new(p) {
+            | Some(p) => Ok(unsafe { HeapCStr::from_raw(self, p) }),
+            | None => Err(io::Error::last_os_error()),
+        }
     }
 
     /// Allocate `size` bytes aligned by `alignment`.
@@ -165,7 +821,67 @@ impl Allocator {
     ///
     /// Returns a unique pointer if called with `size` 0.
     pub fn malloc_aligned(&self, size: usize, alignment: usize) -> *mut u8 {
-        unsafe { mi_heap_malloc_aligned(self.heap, size, alignment) as *mut u8 }
+        self.check(
+            unsafe { mi_heap_malloc_aligned(self.local_heap(), size, alignment) },
+            size,
+            alignment,
+        )
+    }
+
+    /// Allocate `size` bytes aligned by `alignment`, following
+    /// `posix_memalign` conventions.
+    ///
+    /// mimalloc has no heap variant of `posix_memalign`, so the block comes
+    /// from the thread's default heap rather than this allocator's. Returns the
+    /// error code on failure: `EINVAL` if `alignment` isn't a power of two
+    /// multiple of `size_of::<*mut u8>()`, or `ENOMEM` on out-of-memory.
+    pub fn posix_memalign(&self, alignment: usize, size: usize) -> Result<NonNull<u8>, i32> {
+        // a power of two is a multiple of the pointer size once it is at least as large
+        if !alignment.is_power_of_two() || alignment < size_of::<*mut u8>() {
+            return Err(libc::EINVAL);
+        }
+
+        let mut p: *mut c_void = std::ptr::null_mut();
+        match unsafe { mi_posix_memalign(&mut p, alignment, size) } {
+            | 0 => NonNull::new(p as *mut u8).ok_or(libc::EINVAL),
+            | code => Err(code),
+        }
+    }
+
+    /// Allocate `size` bytes aligned by `alignment`, following C11's
+    /// `aligned_alloc` conventions, for code ported from C.
+    ///
+    /// Note the argument order is the reverse of
+    /// [`malloc_aligned`](Allocator::malloc_aligned). Returns null if
+    /// `alignment` isn't a power of two, if `size` isn't a multiple of
+    /// `alignment`, or on out-of-memory.
+    pub fn aligned_alloc(&self, alignment: usize, size: usize) -> *mut u8 {
+        match self.try_aligned_alloc(alignment, size) {
+            | Ok(p) => p.as_ptr(),
+            | Err(AllocError::OutOfMemory) => self.check(std::ptr::null_mut(), size, alignment),
+            | Err(_) => std::ptr::null_mut(),
+        }
+    }
+
+    /// Checked [`aligned_alloc`](Allocator::aligned_alloc).
+    ///
+    /// Returns [`AllocError::InvalidAlignment`] if `alignment` isn't a power of
+    /// two, [`AllocError::InvalidSize`] if `size` isn't a multiple of
+    /// `alignment`, or [`AllocError::OutOfMemory`] on out-of-memory.
+    pub fn try_aligned_alloc(
+        &self,
+        alignment: usize,
+        size: usize,
+    ) -> Result<NonNull<u8>, AllocError> {
+        if !alignment.is_power_of_two() {
+            return Err(AllocError::InvalidAlignment);
+        }
+        if size & (alignment - 1) != 0 {
+            return Err(AllocError::InvalidSize);
+        }
+
+        let p = unsafe { mi_heap_malloc_aligned(self.local_heap(), size, alignment) } as *mut u8;
+        NonNull::new(p).ok_or(AllocError::OutOfMemory)
     }
 
     /// Allocate `size` bytes aligned by `alignment` at a specified `offset`.
@@ -178,7 +894,38 @@ impl Allocator {
     /// Specifically, if `p` is the returned pointer `p.add(offset)` is aligned
     /// to `alignment`.
     pub fn malloc_aligned_at(&self, size: usize, alignment: usize, offset: usize) -> *mut u8 {
-        unsafe { mi_heap_malloc_aligned_at(self.heap, size, alignment, offset) as *mut u8 }
+        let p = unsafe { mi_heap_malloc_aligned_at(self.local_heap(), size, alignment, offset) };
+        self.check(p, size, 1)
+    }
+
+    /// Allocate an uninitialized header `H` directly followed by a payload `T`
+    /// aligned to `payload_align`.
+    ///
+    /// This is [`malloc_aligned_at`](Allocator::malloc_aligned_at) with the
+    /// offset set to `size_of::<H>()`. The payload is aligned to at least
+    /// `align_of::<T>()` and the header to `align_of::<H>()`, whatever
+    /// `payload_align` is. Returns pointers to the header, which is the start
+    /// of the block and the pointer to free, and to the payload.
+    ///
+    /// Returns `None` if `payload_align` is not a power of two, the size
+    /// overflows, or on out-of-memory.
+    pub fn alloc_with_header<H, T>(&self, payload_align: usize) -> Option<(*mut H, *mut T)> {
+        if !payload_align.is_power_of_two() {
+            return None;
+        }
+
+        // the header ends where the payload starts, so it's aligned as long
+        // as the payload is aligned to at least `align_of::<H>()`
+        let alignment = payload_align.max(align_of::<H>()).max(align_of::<T>());
+        let offset = size_of::<H>();
+        let size = offset.checked_add(size_of::<T>())?;
+
+        let header = self.malloc_aligned_at(size, alignment, offset);
+        if header.is_null() {
+            return None;
+        }
+
+        Some((header as *mut H, unsafe { header.add(offset) } as *mut T))
     }
 
     /// Allocate `size` bytes aligned by `alignment`, initialized to zero.
@@ -187,7 +934,11 @@ impl Allocator {
     ///
     /// Returns a unique pointer if called with `size` 0.
     pub fn zalloc_aligned(&self, size: usize, alignment: usize) -> *mut u8 {
-        unsafe { mi_heap_zalloc_aligned(self.heap, size, alignment) as *mut u8 }
+        self.check(
+            unsafe { mi_heap_zalloc_aligned(self.local_heap(), size, alignment) },
+            size,
+            alignment,
+        )
     }
 
     /// Allocate `size` bytes aligned by `alignment` at a specified `offset`,
@@ -196,7 +947,8 @@ impl Allocator {
     /// This is a [`zalloc`](Allocator::zalloc) equivalent of
     /// [`malloc_aligned_at`](Allocator::malloc_aligned_at).
     pub fn zalloc_aligned_at(&self, size: usize, alignment: usize, offset: usize) -> *mut u8 {
-        unsafe { mi_heap_zalloc_aligned_at(self.heap, size, alignment, offset) as *mut u8 }
+        let p = unsafe { mi_heap_zalloc_aligned_at(self.local_heap(), size, alignment, offset) };
+        self.check(p, size, 1)
     }
 
     /// Allocate `size * count` bytes aligned by `alignment`.
@@ -206,7 +958,8 @@ impl Allocator {
     ///
     /// Returns a unique pointer if called with `size * count` 0.
     pub fn calloc_aligned(&self, count: usize, size: usize, alignment: usize) -> *mut u8 {
-        unsafe { mi_heap_calloc_aligned(self.heap, count, size, alignment) as *mut u8 }
+        let p = unsafe { mi_heap_calloc_aligned(self.local_heap(), count, size, alignment) };
+        self.check(p, count.saturating_mul(size), alignment)
     }
 
     /// Allocate `size * count` bytes aligned by `alignment` at a specified
@@ -221,7 +974,9 @@ impl Allocator {
         alignment: usize,
         offset: usize,
     ) -> *mut u8 {
-        unsafe { mi_heap_calloc_aligned_at(self.heap, count, size, alignment, offset) as *mut u8 }
+        let p =
+            unsafe { mi_heap_calloc_aligned_at(self.local_heap(), count, size, alignment, offset) };
+        self.check(p, count.saturating_mul(size), 1)
     }
 
     /// Re-allocate memory to `newsize` bytes, aligned by `alignment`.
@@ -236,9 +991,10 @@ impl Allocator {
     /// larger than the original `size` allocated for `p`, the bytes after
     /// `size` are uninitialized.
     pub fn realloc_aligned(&self, p: *mut u8, new_size: usize, alignment: usize) -> *mut u8 {
-        unsafe {
-            mi_heap_realloc_aligned(self.heap, p as *mut c_void, new_size, alignment) as *mut u8
-        }
+        let q = unsafe {
+            mi_heap_realloc_aligned(self.local_heap(), p as *mut c_void, new_size, alignment)
+        };
+        self.check(q, new_size, alignment)
     }
 
     /// Re-allocate memory to `newsize` bytes aligned by `alignment` at a
@@ -253,10 +1009,16 @@ impl Allocator {
         alignment: usize,
         offset: usize,
     ) -> *mut u8 {
-        unsafe {
-            mi_heap_realloc_aligned_at(self.heap, p as *mut c_void, newsize, alignment, offset)
-                as *mut u8
-        }
+        let q = unsafe {
+            mi_heap_realloc_aligned_at(
+                self.local_heap(),
+                p as *mut c_void,
+                newsize,
+                alignment,
+                offset,
+            )
+        };
+        self.check(q, newsize, 1)
     }
 
     /// Zero initialized [re-allocation](Allocator::realloc).
@@ -266,7 +1028,11 @@ impl Allocator {
     /// [`zalloc`](Allocator::zalloc),
     /// [`zalloc_aligned`](Allocator::zalloc_aligned), ...
     pub fn rezalloc(&self, p: *mut u8, newsize: usize) -> *mut u8 {
-        unsafe { mi_heap_rezalloc(self.heap, p as *mut c_void, newsize) as *mut u8 }
+        self.check(
+            unsafe { mi_heap_rezalloc(self.local_heap(), p as *mut c_void, newsize) },
+            newsize,
+            1,
+        )
     }
 
     /// Zero initialized [re-allocation](Allocator::realloc), following `calloc`
@@ -277,14 +1043,16 @@ impl Allocator {
     /// [`zalloc`](Allocator::zalloc),
     /// [`zalloc_aligned`](Allocator::zalloc_aligned), ...
     pub fn recalloc(&self, p: *mut u8, newcount: usize, size: usize) -> *mut u8 {
-        unsafe { mi_heap_recalloc(self.heap, p as *mut c_void, newcount, size) as *mut u8 }
+        let q = unsafe { mi_heap_recalloc(self.local_heap(), p as *mut c_void, newcount, size) };
+        self.check(q, newcount.saturating_mul(size), 1)
     }
 
     /// Aligned version of [`rezalloc`](Allocator::rezalloc).
     pub fn rezalloc_aligned(&self, p: *mut u8, newsize: usize, alignment: usize) -> *mut u8 {
-        unsafe {
-            mi_heap_rezalloc_aligned(self.heap, p as *mut c_void, newsize, alignment) as *mut u8
-        }
+        let q = unsafe {
+            mi_heap_rezalloc_aligned(self.local_heap(), p as *mut c_void, newsize, alignment)
+        };
+        self.check(q, newsize, alignment)
     }
 
     /// Offset-aligned version of [`rezalloc`](Allocator::rezalloc).
@@ -295,10 +1063,16 @@ impl Allocator {
         alignment: usize,
         offset: usize,
     ) -> *mut u8 {
-        unsafe {
-            mi_heap_rezalloc_aligned_at(self.heap, p as *mut c_void, newsize, alignment, offset)
-                as *mut u8
-        }
+        let q = unsafe {
+            mi_heap_rezalloc_aligned_at(
+                self.local_heap(),
+                p as *mut c_void,
+                newsize,
+                alignment,
+                offset,
+            )
+        };
+        self.check(q, newsize, 1)
     }
 
     /// Aligned version of [`recalloc`](Allocator::recalloc).
@@ -309,10 +1083,16 @@ impl Allocator {
         size: usize,
         alignment: usize,
     ) -> *mut u8 {
-        unsafe {
-            mi_heap_recalloc_aligned(self.heap, p as *mut c_void, newcount, size, alignment)
-                as *mut u8
-        }
+        let q = unsafe {
+            mi_heap_recalloc_aligned(
+                self.local_heap(),
+                p as *mut c_void,
+                newcount,
+                size,
+                alignment,
+            )
+        };
+        self.check(q, newcount.saturating_mul(size), alignment)
     }
 
     /// Offset-aligned version of [`recalloc`](Allocator::recalloc).
@@ -324,16 +1104,17 @@ impl Allocator {
         alignment: usize,
         offset: usize,
     ) -> *mut u8 {
-        unsafe {
+        let q = unsafe {
             mi_heap_recalloc_aligned_at(
-                self.heap,
+                self.local_heap(),
                 p as *mut c_void,
                 newcount,
                 size,
                 alignment,
                 offset,
-            ) as *mut u8
-        }
+            )
+        };
+        self.check(q, newcount.saturating_mul(size), 1)
     }
 
     /// Does a heap contain a pointer to a previously allocated block?
@@ -345,7 +1126,7 @@ impl Allocator {
     ///
     /// See [`check_owned`](Allocator::check_owned).
     pub fn contains_block(&self, p: *const u8) -> bool {
-        unsafe { mi_heap_contains_block(self.heap, p as *const c_void) }
+        unsafe { mi_heap_contains_block(self.heap(), p as *const c_void) }
     }
 
     /// Check safely if any pointer is part of a heap.
@@ -356,10 +1137,20 @@ impl Allocator {
     ///
     /// Note: expensive function, linear in the pages in the heap.
     ///
-    /// See [`contains_block`](Allocator::contains_block), [`get_default`], and
-    /// [`is_in_region`]
+    /// See [`contains_block`](Allocator::contains_block),
+    /// [`check_owned_by_default`], and [`is_in_heap_region`]
     pub fn check_owned(&self, p: *const u8) -> bool {
-        unsafe { mi_heap_check_owned(self.heap, p as *const c_void) }
+        unsafe { mi_heap_check_owned(self.local_heap(), p as *const c_void) }
+    }
+
+    /// Apply the allocation policy to the result of allocating `size` bytes
+    /// aligned by `align`.
+    fn check(&self, p: *mut c_void, size: usize, align: usize) -> *mut u8 {
+        if p.is_null() && self.policy == AllocPolicy::Abort {
+            handle_alloc_error(Layout::from_size_align(size, align).unwrap_or(Layout::new::<u8>()));
+        }
+
+        p as *mut u8
     }
 
     /// Visit all areas and blocks in `heap`.
@@ -380,6 +1171,1834 @@ impl Allocator {
         visitor: mi_block_visit_fun,
         arg: *mut u8,
     ) -> bool {
-        unsafe { mi_heap_visit_blocks(self.heap, visit_all_blocks, visitor, arg as *mut c_void) }
+        unsafe {
+            mi_heap_visit_blocks(
+                self.local_heap(),
+                visit_all_blocks,
+                visitor,
+                arg as *mut c_void,
+            )
+        }
+    }
+
+    /// Move `value` into a block allocated from this heap.
+    ///
+    /// Returns `None` on out-of-memory, dropping `value`. The value is dropped
+    /// and the block freed when the returned [`HeapBox`] is dropped.
+    pub fn alloc_box<T>(&self, value: T) -> Option<HeapBox<'_, T>> {
+        let p = self.alloc_array::<T>(1, false)?;
+        unsafe {
+            p.as_ptr().write(value);
+            Some(HeapBox::from_raw(self, p))
+        }
+    }
+
+    /// Move `value` into a block aligned to at least `align`, e.g. to keep it
+    /// on its own cache line.
+    ///
+    /// The block is aligned to the larger of `align` and `align_of::<T>()`.
+    /// Returns `None` if `align` is not a power of two or on out-of-memory,
+    /// dropping `value`.
+    pub fn alloc_box_aligned<T>(&self, value: T, align: usize) -> Option<HeapBox<'_, T>> {
+        if !align.is_power_of_two() {
+            return None;
+        }
+
+        let align = align.max(align_of::<T>());
+        let p = if size_of::<T>() == 0 {
+            // like `alloc_array`, zero-sized types aren't allocated
+            NonNull::new(align as *mut T)?
+        } else {
+            NonNull::new(self.malloc_aligned(size_of::<T>(), align) as *mut T)?
+        };
+
+        unsafe {
+            p.as_ptr().write(value);
+            Some(HeapBox::from_raw(self, p))
+        }
+    }
+
+    /// Place `value` in a block allocated from this heap, the same as
+    /// [`alloc_box`](Allocator::alloc_box).
+    pub fn place<T>(&self, value: T) -> Option<HeapBox<'_, T>> {
+        self.alloc_box(value)
+    }
+
+    /// Allocate a block for a `T`, then compute the value with `f` and place
+    /// it there.
+    ///
+    /// `f` only runs once the allocation succeeded, so an expensive value
+    /// isn't built just to be dropped on out-of-memory. Returns `None` on
+    /// out-of-memory. If `f` panics the block is freed.
+    pub fn place_with<T, F>(&self, f: F) -> Option<HeapBox<'_, T>>
+    where
+        F: FnOnce() -> T, {
+        struct FreeOnUnwind<'a, T> {
+            alloc: &'a Allocator,
+            p: NonNull<T>,
+        }
+
+        impl<T> Drop for FreeOnUnwind<'_, T> {
+            fn drop(&mut self) {
+                self.alloc.free_array(self.p);
+            }
+        }
+
+        let p = self.alloc_array::<T>(1, false)?;
+        let guard = FreeOnUnwind { alloc: self, p };
+        let value = f();
+        std::mem::forget(guard);
+
+        unsafe {
+            p.as_ptr().write(value);
+            Some(HeapBox::from_raw(self, p))
+        }
+    }
+
+    /// Allocate a zero-initialized `T` without writing it twice.
+    ///
+    /// The block comes from `mi_heap_zalloc_aligned`, so it's zeroed once by
+    /// mimalloc; `T: Zeroable` guarantees all zeroes is a valid `T`. Returns
+    /// `None` on out-of-memory.
+    pub fn alloc_zeroed_box<T: Zeroable>(&self) -> Option<HeapBox<'_, T>> {
+        let p = self.alloc_array::<T>(1, true)?;
+        Some(unsafe { HeapBox::from_raw(self, p) })
+    }
+
+    /// Allocate a zero-initialized slice of `len` elements of `T`.
+    ///
+    /// Returns `None` if `len * size_of::<T>()` overflows or on out-of-memory.
+    /// The slice is freed when the returned [`HeapSlice`] is dropped.
+    pub fn alloc_slice<T: Zeroable>(&self, len: usize) -> Option<HeapSlice<'_, T>> {
+        let p = self.alloc_array::<T>(len, true)?;
+        Some(unsafe { HeapSlice::from_raw_parts(self, p, len) })
+    }
+
+    /// [`alloc_zeroed_box`](Allocator::alloc_zeroed_box) for
+    /// `bytemuck::Zeroable` types.
+    #[cfg(feature = "bytemuck")]
+    pub fn alloc_zeroed_box_bytemuck<T: bytemuck::Zeroable>(&self) -> Option<HeapBox<'_, T>> {
+        let p = self.alloc_array::<T>(1, true)?;
+        Some(unsafe { HeapBox::from_raw(self, p) })
+    }
+
+    /// [`alloc_slice`](Allocator::alloc_slice) for `bytemuck::Zeroable` types.
+    #[cfg(feature = "bytemuck")]
+    pub fn alloc_slice_bytemuck<T: bytemuck::Zeroable>(
+        &self,
+        len: usize,
+    ) -> Option<HeapSlice<'_, T>> {
+        let p = self.alloc_array::<T>(len, true)?;
+        Some(unsafe { HeapSlice::from_raw_parts(self, p, len) })
+    }
+
+    /// Allocate room for `cap` elements of `T` once and fill it from `iter`.
+    ///
+    /// Stops at `cap` elements or when `iter` ends, whichever comes first. If
+    /// fewer than `cap` items were written the slice is shorter, but the block
+    /// isn't reallocated. Returns `None` if `cap * size_of::<T>()` overflows or
+    /// on out-of-memory.
+    pub fn collect_into<T, I>(&self, iter: I, cap: usize) -> Option<HeapSlice<'_, T>>
+    where
+        I: IntoIterator<Item = T>, {
+        let p = self.alloc_array::<T>(cap, false)?;
+        let mut slice = unsafe { HeapSlice::from_raw_parts(self, p, 0) };
+        // bump the length after every write so a panicking iterator drops
+        // only what was written
+        for (i, item) in iter.into_iter().take(cap).enumerate() {
+            unsafe {
+                p.as_ptr().add(i).write(item);
+                slice.set_len(i + 1);
+            }
+        }
+
+        Some(slice)
+    }
+
+    /// Allocate a zero-initialized slice of at least `min_len` elements of `T`,
+    /// rounded up to mimalloc's size class.
+    ///
+    /// Returns the slice of `min_len` elements along with the capacity of the
+    /// block in elements. The block is zeroed up to that capacity, so callers
+    /// growing a buffer can use the extra elements for free. For zero-sized
+    /// types the capacity is `usize::MAX`, like `Vec`. Returns `None` if
+    /// `min_len * size_of::<T>()` overflows or on out-of-memory.
+    pub fn alloc_slice_rounded<T: Zeroable>(
+        &self,
+        min_len: usize,
+    ) -> Option<(HeapSlice<'_, T>, usize)> {
+        if size_of::<T>() == 0 {
+            let p = self.alloc_array::<T>(min_len, true)?;
+            return Some((
+                unsafe { HeapSlice::from_raw_parts(self, p, min_len) },
+                usize::MAX,
+            ));
+        }
+
+        let size = min_len.checked_mul(size_of::<T>())?;
+        let rounded = unsafe { mi_good_size(size) } / size_of::<T>().max(1);
+
+        let p = self.calloc_aligned(rounded, size_of::<T>(), align_of::<T>()) as *mut T;
+        let p = NonNull::new(p)?;
+        let usable = unsafe { mi::allocator::mi_usable_size(p.as_ptr() as *const c_void) };
+        let capacity = usable.checked_div(size_of::<T>()).unwrap_or(min_len);
+
+        Some((
+            unsafe { HeapSlice::from_raw_parts(self, p, min_len) },
+            capacity,
+        ))
+    }
+
+    /// Allocate an uninitialized slice of `len` elements of `T`.
+    ///
+    /// Returns `None` if `len * size_of::<T>()` overflows or on out-of-memory.
+    /// This is the [`alloc_slice`](Allocator::alloc_slice) equivalent for
+    /// types which can't be zero-initialized.
+    pub fn alloc_slice_uninit<T>(&self, len: usize) -> Option<HeapSlice<'_, MaybeUninit<T>>> {
+        let p = self.alloc_array::<MaybeUninit<T>>(len, false)?;
+        Some(unsafe { HeapSlice::from_raw_parts(self, p, len) })
+    }
+
+    /// Allocate an uninitialized array of `len` elements of `T`, zeroed if
+    /// `zeroed` is set.
+    ///
+    /// Follows the std conventions for zero-sized arrays, of zero-sized types
+    /// or with `len` 0: nothing is allocated and a dangling pointer is
+    /// returned, which [`free_array`](Allocator::free_array) ignores. Returns
+    /// `None` if `len * size_of::<T>()` overflows or on out-of-memory.
+    fn alloc_array<T>(&self, len: usize, zeroed: bool) -> Option<NonNull<T>> {
+        let size = len.checked_mul(size_of::<T>())?;
+        if size == 0 {
+            return Some(NonNull::dangling());
+        }
+
+        let p = if zeroed {
+            self.zalloc_aligned(size, align_of::<T>())
+        } else {
+            self.malloc_aligned(size, align_of::<T>())
+        };
+        NonNull::new(p as *mut T)
+    }
+
+    /// Free an array allocated by [`alloc_array`](Allocator::alloc_array).
+    ///
+    /// This is a no-op for zero-sized types and dangling pointers, which
+    /// mimalloc never hands out.
+    pub(crate) fn free_array<T>(&self, p: NonNull<T>) {
+        if size_of::<T>() != 0 && p != NonNull::dangling() {
+            self.free(p.as_ptr() as *mut u8);
+        }
+    }
+
+    /// Move the elements of `src` into a new slice allocated from `dst`, and
+    /// free `src`, e.g. to balance load between allocators.
+    ///
+    /// An empty `src` gives an empty slice without allocating. Fails on
+    /// out-of-memory, handing back the untouched `src`.
+    pub fn migrate<'a, 'b, T: Copy>(
+        &self,
+        src: HeapSlice<'a, T>,
+        dst: &'b Allocator,
+    ) -> Result<HeapSlice<'b, T>, HeapSlice<'a, T>> {
+        debug_assert!(std::ptr::eq(src.allocator(), self));
+
+        let Some(p) = dst.alloc_array::<T>(src.len(), false) else {
+            return Err(src);
+        };
+        unsafe {
+            p.as_ptr().copy_from_nonoverlapping(src.as_ptr(), src.len());
+            Ok(HeapSlice::from_raw_parts(dst, p, src.len()))
+        }
+    }
+
+    /// Allocate memory fitting `layout`.
+    ///
+    /// Follows the std conventions for zero-sized layouts: no memory is
+    /// allocated, and a dangling pointer aligned to `layout.align()` is
+    /// returned instead. Otherwise returns null on out-of-memory.
+    pub fn alloc_layout(&self, layout: Layout) -> *mut u8 {
+        if layout.size() == 0 {
+            return layout.align() as *mut u8;
+        }
+
+        self.malloc_aligned(layout.size(), layout.align())
+    }
+
+    /// Free memory allocated by [`alloc_layout`](Allocator::alloc_layout) with
+    /// the same `layout`.
+    ///
+    /// This is a no-op for zero-sized layouts.
+    pub fn dealloc_layout(&self, p: *mut u8, layout: Layout) {
+        if layout.size() == 0 {
+            return;
+        }
+
+        unsafe { mi_free_size_aligned(p as *mut c_void, layout.size(), layout.align()) }
+    }
+
+    /// A closure allocating from this heap, for container code taking
+    /// allocation functions.
+    ///
+    /// It behaves like [`alloc_layout`](Allocator::alloc_layout); free its
+    /// blocks with [`dealloc_fn`](Allocator::dealloc_fn).
+    pub fn alloc_fn(&self) -> impl Fn(Layout) -> *mut u8 + '_ {
+        move |layout| self.alloc_layout(layout)
+    }
+
+    /// A closure freeing blocks from [`alloc_fn`](Allocator::alloc_fn).
+    ///
+    /// It behaves like [`dealloc_layout`](Allocator::dealloc_layout).
+    pub fn dealloc_fn(&self) -> impl Fn(*mut u8, Layout) + '_ {
+        move |p, layout| self.dealloc_layout(p, layout)
+    }
+
+    /// Re-allocate a typed array of `old_len` elements at `p` to hold
+    /// `new_len` elements.
+    ///
+    /// The typed counterpart of [`reallocn`](Allocator::reallocn). Returns
+    /// null if `new_len * size_of::<T>()` overflows or on out-of-memory, in
+    /// which case `p` is left untouched. The first `min(old_len, new_len)`
+    /// elements are preserved, and any new elements are uninitialized.
+    pub fn realloc_slice_raw<T>(&self, p: *mut T, old_len: usize, new_len: usize) -> *mut T {
+        if new_len.checked_mul(size_of::<T>()).is_none() {
+            return std::ptr::null_mut();
+        }
+
+        debug_assert!(
+            p.is_null() ||
+                unsafe { mi::allocator::mi_usable_size(p as *const c_void) } >=
+                    old_len * size_of::<T>()
+        );
+
+        self.reallocn(p as *mut u8, new_len, size_of::<T>()) as *mut T
+    }
+
+    /// Re-allocate the typed array at `p` to hold `new_count` elements, like
+    /// BSD's `reallocarray`.
+    ///
+    /// Returns `None` if `new_count * size_of::<T>()` overflows, checked
+    /// before calling into mimalloc, or on out-of-memory. In both cases `p`
+    /// is left untouched and still has to be freed.
+    pub fn realloc_array<T>(&self, p: *mut T, new_count: usize) -> Option<NonNull<T>> {
+        new_count.checked_mul(size_of::<T>())?;
+
+        NonNull::new(self.reallocn(p as *mut u8, new_count, size_of::<T>()) as *mut T)
+    }
+
+    /// Resize `slice` to `new_len` elements, preserving the first
+    /// `min(old_len, new_len)` elements and zeroing any new ones.
+    ///
+    /// Fails if `new_len * size_of::<T>()` overflows or, when growing, on
+    /// out-of-memory. The untouched `slice` is handed back in that case, as
+    /// mimalloc leaves the original block alive. Shrinking can't fail: if the
+    /// block can't be moved to a smaller one it's kept as is.
+    pub fn realloc_slice<'a, T: Zeroable>(
+        &'a self,
+        slice: HeapSlice<'a, T>,
+        new_len: usize,
+    ) -> Result<HeapSlice<'a, T>, HeapSlice<'a, T>> {
+        let old_len = slice.len();
+        let p = self.resize_raw(slice, new_len)?;
+        if new_len > old_len {
+            unsafe { p.as_ptr().add(old_len).write_bytes(0, new_len - old_len) };
+        }
+
+        Ok(unsafe { HeapSlice::from_raw_parts(self, p, new_len) })
+    }
+
+    /// Resize `slice` to `new_len` elements, preserving the first
+    /// `min(old_len, new_len)` elements and leaving any new ones
+    /// uninitialized.
+    ///
+    /// This is the [`realloc_slice`](Allocator::realloc_slice) equivalent for
+    /// types which can't be zero-initialized, and fails the same way.
+    pub fn realloc_slice_uninit<'a, T>(
+        &'a self,
+        slice: HeapSlice<'a, MaybeUninit<T>>,
+        new_len: usize,
+    ) -> Result<HeapSlice<'a, MaybeUninit<T>>, HeapSlice<'a, MaybeUninit<T>>> {
+        let p = self.resize_raw(slice, new_len)?;
+        Ok(unsafe { HeapSlice::from_raw_parts(self, p, new_len) })
+    }
+
+    /// Re-allocate the block of `slice` to hold `new_len` elements, dropping
+    /// any elements past `new_len` once the resize can't fail anymore.
+    fn resize_raw<'a, T>(
+        &'a self,
+        slice: HeapSlice<'a, T>,
+        new_len: usize,
+    ) -> Result<NonNull<T>, HeapSlice<'a, T>> {
+        let Some(size) = new_len.checked_mul(size_of::<T>()) else {
+            return Err(slice);
+        };
+
+        let (p, len) = slice.into_raw_parts();
+        // an empty slice may not have been allocated at all
+        let allocated = size_of::<T>() != 0 && p != NonNull::dangling();
+
+        if new_len > len {
+            if size_of::<T>() == 0 {
+                return Ok(p);
+            }
+
+            let old = if allocated {
+                p.as_ptr()
+            } else {
+                std::ptr::null_mut()
+            };
+            let q = self.realloc_aligned(old as *mut u8, size, align_of::<T>()) as *mut T;
+            // on failure the original block is still alive, give it back
+            return NonNull::new(q)
+                .ok_or_else(|| unsafe { HeapSlice::from_raw_parts(self, p, len) });
+        }
+
+        unsafe {
+            let tail = p.as_ptr().add(new_len);
+            std::ptr::drop_in_place(std::ptr::slice_from_raw_parts_mut(tail, len - new_len));
+        }
+        if !allocated {
+            return Ok(p);
+        }
+
+        // the old block still fits the shorter slice if it can't be moved
+        let q = self.realloc_aligned(p.as_ptr() as *mut u8, size, align_of::<T>()) as *mut T;
+        Ok(NonNull::new(q).unwrap_or(p))
+    }
+
+    /// Allocate `layout` out of the thread's backing heap and deliberately leak
+    /// it, returning a `'static` slice.
+    ///
+    /// The backing heap can't be deleted or destroyed and the block is never
+    /// freed, so this is the intended way to build process-lifetime data such
+    /// as static tables. Note the block does not come from this allocator's
+    /// heap.
+    ///
+    /// Calls [`handle_alloc_error`] on out-of-memory.
+    pub fn alloc_static(&self, layout: Layout) -> &'static mut [u8] {
+        let p =
+            unsafe { mi_heap_malloc_aligned(mi_heap_get_backing(), layout.size(), layout.align()) };
+        if p.is_null() {
+            handle_alloc_error(layout);
+        }
+
+        unsafe { std::slice::from_raw_parts_mut(p as *mut u8, layout.size()) }
+    }
+
+    /// Allocate `layout` out of the thread's backing heap instead of this
+    /// allocator's heap.
+    ///
+    /// Deleting a heap migrates its live blocks to the default heap, changing
+    /// which heap owns them. The backing heap is never deleted or destroyed
+    /// (except by exiting the thread), so a pinned block stays owned by the
+    /// same heap until it's freed, even after this allocator is dropped. It
+    /// must still be freed explicitly.
+    ///
+    /// Returns `None` on out-of-memory.
+    pub fn alloc_pinned(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let p =
+            unsafe { mi_heap_malloc_aligned(mi_heap_get_backing(), layout.size(), layout.align()) };
+        NonNull::new(p as *mut u8)
+    }
+
+    /// Summarize every area in the heap.
+    ///
+    /// This is a safe [`visit_blocks`](Allocator::visit_blocks) which only
+    /// visits the areas, not the individual blocks.
+    pub fn areas(&self) -> Vec<AreaInfo> {
+        let mut areas = Vec::new();
+        self.walk(false, |area, block, _| {
+            if block.is_null() {
+                areas.push(AreaInfo::from(area));
+            }
+            true
+        });
+        areas
+    }
+
+    /// List every live block in the heap.
+    ///
+    /// This is a point-in-time snapshot: the pointers aren't owned, and dangle
+    /// once their blocks are freed.
+    ///
+    /// Note: expensive function, linear in the number of blocks in the heap.
+    pub fn snapshot_blocks(&self) -> Vec<BlockRef> {
+        let mut blocks = Vec::new();
+        self.walk(true, |_, block, block_size| {
+            if !block.is_null() {
+                blocks.push(BlockRef {
+                    ptr: block,
+                    size: unsafe { mi::allocator::mi_usable_size(block as *const c_void) },
+                    block_size,
+                });
+            }
+            true
+        });
+        blocks
+    }
+
+    /// A stable hash of the sizes of the live blocks in the heap.
+    ///
+    /// The sorted block sizes are hashed with 64-bit FNV-1a; addresses are left
+    /// out as they aren't deterministic. Replaying a deterministic workload on
+    /// a fresh heap yields the same hash, across runs and builds.
+    ///
+    /// Note: expensive function, linear in the number of blocks in the heap.
+    pub fn snapshot_hash(&self) -> u64 {
+        const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+
+        let mut sizes = Vec::new();
+        self.walk(true, |_, block, block_size| {
+            if !block.is_null() {
+                sizes.push(block_size as u64);
+            }
+            true
+        });
+        sizes.sort_unstable();
+
+        sizes
+            .iter()
+            .flat_map(|size| size.to_le_bytes())
+            .fold(FNV_OFFSET, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+            })
+    }
+
+    /// Sum the memory held by the heap over all of its areas.
+    ///
+    /// mimalloc tracks the number of blocks in use per area, so this only walks
+    /// the areas and never the individual blocks.
+    pub fn live_bytes(&self) -> HeapUsage {
+        let mut usage = HeapUsage::default();
+        self.walk(false, |area, block, _| {
+            if block.is_null() {
+                usage.add_area(&AreaInfo::from(area));
+            }
+            true
+        });
+        usage
+    }
+
+    /// Free every live block in the heap whose area `block_size` equals
+    /// `block_size`.
+    ///
+    /// The blocks are collected while walking the heap and only freed once the
+    /// walk has finished, as the heap can't be modified while it's being
+    /// visited. Returns the number of blocks that were freed.
+    pub fn free_size_class(&self, block_size: usize) -> usize {
+        let mut blocks = Vec::new();
+        self.walk(true, |area, block, _| {
+            if !block.is_null() && area.block_size == block_size {
+                blocks.push(block);
+            }
+            true
+        });
+
+        for block in &blocks {
+            self.free(*block);
+        }
+
+        blocks.len()
+    }
+
+    /// Count the live blocks in the heap.
+    ///
+    /// Note: expensive function, linear in the number of blocks in the heap.
+    pub fn live_block_count(&self) -> usize {
+        let mut count = 0;
+        self.walk(true, |_, block, _| {
+            if !block.is_null() {
+                count += 1;
+            }
+            true
+        });
+        count
+    }
+
+    /// Count the live blocks in the heap per block size class.
+    ///
+    /// Sizes are the rounded sizes of mimalloc's bins, not the sizes that were
+    /// requested.
+    ///
+    /// Note: expensive function, linear in the number of blocks in the heap.
+    pub fn block_histogram(&self) -> BTreeMap<usize, usize> {
+        let mut histogram = BTreeMap::new();
+        self.walk(true, |_, block, block_size| {
+            if !block.is_null() {
+                *histogram.entry(block_size).or_insert(0) += 1;
+            }
+            true
+        });
+        histogram
+    }
+
+    /// The share of committed memory in the heap that isn't used by live
+    /// blocks, between `0.0` and `1.0`.
+    ///
+    /// This is how much committed memory is sitting in free blocks. Returns
+    /// `0.0` if nothing is committed.
+    ///
+    /// Note: walks every area in the heap, but not the blocks.
+    pub fn fragmentation(&self) -> f64 {
+        let mut used = 0;
+        let mut committed = 0;
+        self.walk(false, |area, _, _| {
+            // `used` is the number of blocks in use, not bytes
+            used += area.used * area.block_size;
+            committed += area.committed;
+            true
+        });
+
+        if committed == 0 {
+            return 0.0;
+        }
+
+        (1.0 - used as f64 / committed as f64).clamp(0.0, 1.0)
+    }
+
+    /// Allocate across a matrix of sizes and alignments, asserting every block
+    /// is aligned and at least as large as requested, then free them all and
+    /// assert the heap has no live blocks left.
+    ///
+    /// This is a self-test for the aligned allocation paths, meant to catch
+    /// regressions when the vendored mimalloc is bumped.
+    #[cfg(test)]
+    pub(crate) fn verify_aligned_invariants(&self) {
+        const SIZES: [usize; 8] = [0, 1, 7, 64, 100, 1024, 4097, 1 << 20];
+        const ALIGNMENTS: [usize; 7] = [1, 2, 8, 16, 64, 512, 4096];
+
+        let mut blocks = Vec::with_capacity(SIZES.len() * ALIGNMENTS.len());
+        for size in SIZES {
+            for alignment in ALIGNMENTS {
+                let p = self.malloc_aligned(size, alignment);
+                assert!(
+                    !p.is_null(),
+                    "size {} align {} returned null",
+                    size,
+                    alignment
+                );
+                assert_eq!(
+                    p as usize % alignment,
+                    0,
+                    "size {} align {}",
+                    size,
+                    alignment
+                );
+
+                let usable = unsafe { mi::allocator::mi_usable_size(p as *const c_void) };
+                assert!(
+                    usable >= size,
+                    "size {} align {} usable {}",
+                    size,
+                    alignment,
+                    usable
+                );
+
+                blocks.push(p);
+            }
+        }
+
+        for p in blocks {
+            self.free(p);
+        }
+
+        assert_eq!(self.live_block_count(), 0);
+    }
+
+    /// Visit all areas and blocks in the heap with a closure.
+    ///
+    /// This is [`visit_blocks`](Allocator::visit_blocks) without the raw
+    /// callback: the `visitor` is handed the area, the block (null for the
+    /// per-area call) and the block size, and returns `false` to stop early.
+    fn walk<F>(&self, visit_all_blocks: bool, mut visitor: F) -> bool
+    where
+        F: FnMut(&mi_heap_area_t, *mut u8, usize) -> bool, {
+        unsafe extern "C" fn trampoline<F>(
+            _heap: *const mi_heap_t,
+            area: *const mi_heap_area_t,
+            block: *mut c_void,
+            block_size: usize,
+            arg: *mut c_void,
+        ) -> bool
+        where
+            F: FnMut(&mi_heap_area_t, *mut u8, usize) -> bool, {
+            let visitor = &mut *(arg as *mut F);
+            visitor(&*area, block as *mut u8, block_size)
+        }
+
+        unsafe {
+            mi_heap_visit_blocks(
+                self.local_heap(),
+                visit_all_blocks,
+                Some(trampoline::<F>),
+                &mut visitor as *mut F as *mut c_void,
+            )
+        }
+    }
+}
+
+/// Is `p` in a region of memory managed by mimalloc?
+///
+/// `p` may be any pointer, such as one handed over by foreign code. This is
+/// relatively fast, but doesn't say which heap owns the block.
+///
+/// See [`Allocator::check_owned`] for a single heap.
+pub fn is_in_heap_region(p: *const u8) -> bool {
+    unsafe { mi_is_in_heap_region(p as *const c_void) }
+}
+
+/// Return the available bytes in the block at `p`, like libc's
+/// `malloc_usable_size`.
+///
+/// Uses mimalloc's `malloc_size` entry point on Apple platforms and its
+/// `malloc_usable_size` one elsewhere, matching what profilers and other tools
+/// query when mimalloc is the process allocator. Returns 0 for null.
+pub fn malloc_usable_size(p: *const u8) -> usize {
+    #[cfg(target_vendor = "apple")]
+    unsafe {
+        mi::allocator::mi_malloc_size(p as *const c_void)
+    }
+    #[cfg(not(target_vendor = "apple"))]
+    unsafe {
+        mi::allocator::mi_malloc_usable_size(p as *const c_void)
+    }
+}
+
+/// The largest size mimalloc 2.1.2 serves from its regular pages, half a
+/// segment: 16MiB on 64-bit targets, 2MiB on 32-bit ones.
+#[cfg(target_pointer_width = "64")]
+const LARGE_OBJ_SIZE_MAX: usize = 16 << 20;
+#[cfg(not(target_pointer_width = "64"))]
+const LARGE_OBJ_SIZE_MAX: usize = 2 << 20;
+
+/// Whether an allocation of `size` bytes takes mimalloc's huge path, see
+/// [`Allocator::malloc_huge`].
+///
+/// That's the case above half a segment: 16MiB on 64-bit targets, 2MiB on
+/// 32-bit ones.
+pub fn is_huge(size: usize) -> bool {
+    size > LARGE_OBJ_SIZE_MAX
+}
+
+/// Check safely if any pointer is part of the default heap of this thread.
+///
+/// `p` may be any pointer. Returns `true` if `p` points to a block in the
+/// default heap, false otherwise.
+///
+/// Note: expensive function, linear in the pages in the heap.
+///
+/// See [`is_in_heap_region`].
+pub fn check_owned_by_default(p: *const u8) -> bool {
+    unsafe { mi_check_owned(p as *const c_void) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn live_blocks_of(alloc: &Allocator, block_size: usize) -> usize {
+        let mut count = 0;
+        alloc.walk(true, |area, block, _| {
+            if !block.is_null() && area.block_size == block_size {
+                count += 1;
+            }
+            true
+        });
+        count
+    }
+
+    #[test]
+    fn free_size_class_only_frees_matching_blocks() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        let small: Vec<*mut u8> = (0..8).map(|_| alloc.malloc(16)).collect();
+        let large: Vec<*mut u8> = (0..4).map(|_| alloc.malloc(64)).collect();
+        assert!(small.iter().chain(large.iter()).all(|p| !p.is_null()));
+
+        assert_eq!(alloc.free_size_class(16), small.len());
+        assert_eq!(live_blocks_of(&alloc, 16), 0);
+        assert_eq!(live_blocks_of(&alloc, 64), large.len());
+
+        for p in large {
+            alloc.free(p);
+        }
+    }
+
+    #[test]
+    fn alloc_slice_empty() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let slice = alloc.alloc_slice::<u64>(0).unwrap();
+        assert!(slice.is_empty());
+    }
+
+    #[test]
+    fn foreign_thread_cant_allocate() {
+        let alloc = Arc::new(Allocator::new(1, unsafe { mi_heap_new() }));
+        let p = alloc.malloc(64) as usize;
+
+        let remote = alloc.clone();
+        let result = std::thread::spawn(move || {
+            // freeing is fine from any thread
+            remote.free(p as *mut u8);
+            remote.malloc(64) as usize
+        })
+        .join();
+        assert!(result.is_err());
+        assert_eq!(alloc.live_block_count(), 0);
+
+        // nor can the heap be walked
+        let remote = alloc.clone();
+        let result = std::thread::spawn(move || remote.live_block_count()).join();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn collect_force_purges() {
+        use cesium_libmimalloc_sys::{
+            mi_option_purge_delay,
+            options::mi_option_set,
+        };
+
+        use crate::stats::process_info;
+
+        if !crate::is_isolated() {
+            assert!(crate::run_isolated(
+                "allocator::tests::collect_force_purges"
+            ));
+            return;
+        }
+
+        // keep light collections from purging anything in this test
+        unsafe { mi_option_set(mi_option_purge_delay, 60_000) };
+
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let blocks = alloc.malloc_batch(1024, 64 << 10);
+        assert!(!blocks.is_empty());
+        for p in blocks {
+            unsafe { p.write_bytes(1, 64 << 10) };
+            alloc.free(p);
+        }
+
+        alloc.collect_light();
+        let light = process_info().current_commit;
+        alloc.collect_force();
+        let forced = process_info().current_commit;
+
+        assert!(forced < light, "{forced} >= {light}");
+        assert_eq!(alloc.live_bytes().committed, 0);
+    }
+
+    #[test]
+    fn malloc_batch() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        if crate::is_isolated() {
+            use crate::options::OptionsBuilder;
+
+            // the heap runs out of pages in the segments already reserved
+            OptionsBuilder::new().limit_os_alloc(true).build();
+            assert!(alloc.malloc_batch(1 << 20, 64).is_empty());
+            assert_eq!(alloc.live_block_count(), 0);
+            return;
+        }
+
+        assert!(alloc.malloc_batch(usize::MAX, 8).is_empty());
+        assert!(alloc.malloc_batch(usize::MAX / 16, 8).is_empty());
+        assert_eq!(alloc.live_block_count(), 0);
+
+        let blocks = alloc.malloc_batch(1000, 48);
+        assert_eq!(blocks.len(), 1000);
+        assert!(blocks.iter().all(|p| !p.is_null()));
+        assert_eq!(alloc.live_block_count(), 1000);
+        for p in blocks {
+            alloc.free(p);
+        }
+        assert_eq!(alloc.live_block_count(), 0);
+
+        assert!(crate::run_isolated("allocator::tests::malloc_batch"));
+    }
+
+    #[test]
+    fn same_heap() {
+        let a = Allocator::new(1, unsafe { mi_heap_new() });
+        let b = Allocator::new(2, unsafe { mi_heap_new() });
+
+        assert!(a.is_same_heap(a.heap()));
+        assert!(!a.is_same_heap(b.heap()));
+        assert!(!a.is_same_heap(std::ptr::null()));
+    }
+
+    #[test]
+    fn c11_aligned_alloc() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        let p = alloc.aligned_alloc(64, 256);
+        assert!(!p.is_null());
+        assert_eq!(p as usize % 64, 0);
+        alloc.free(p);
+
+        assert!(alloc.aligned_alloc(64, 100).is_null());
+        assert_eq!(
+            alloc.try_aligned_alloc(64, 100),
+            Err(AllocError::InvalidSize)
+        );
+        assert_eq!(
+            alloc.try_aligned_alloc(48, 96),
+            Err(AllocError::InvalidAlignment)
+        );
+
+        let p = alloc.try_aligned_alloc(4096, 8192).unwrap();
+        assert_eq!(p.as_ptr() as usize % 4096, 0);
+        alloc.free(p.as_ptr());
+    }
+
+    #[test]
+    fn huge_allocations() {
+        assert!(!is_huge(4096));
+        assert!(!is_huge(LARGE_OBJ_SIZE_MAX));
+        assert!(is_huge(LARGE_OBJ_SIZE_MAX + 1));
+
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let size = 2 * LARGE_OBJ_SIZE_MAX;
+        assert!(is_huge(size));
+
+        let p = alloc.malloc_huge(size);
+        assert!(!p.is_null());
+        unsafe { p.write_bytes(1, size) };
+        assert!(alloc.contains_block(p));
+        alloc.free(p);
+        assert_eq!(alloc.live_block_count(), 0);
+    }
+
+    #[test]
+    fn shrink_keeps_pointer() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let p = alloc.malloc(1024);
+        unsafe { p.write_bytes(3, 1024) };
+
+        assert_eq!(alloc.shrink(p, 1024, 512), p);
+        assert_eq!(alloc.shrink(p, 512, 8), p);
+        assert_eq!(unsafe { *p.add(7) }, 3);
+
+        let q = alloc.shrink(p, 8, 4096);
+        assert!(!q.is_null());
+        assert!(malloc_usable_size(q) >= 4096);
+        alloc.free(q);
+    }
+
+    #[test]
+    fn migrate_between_heaps() {
+        let a = Allocator::new(1, unsafe { mi_heap_new() });
+        let b = Allocator::new(2, unsafe { mi_heap_new() });
+
+        let mut src = a.alloc_slice::<[u8; 1024]>(1).unwrap();
+        for (i, byte) in src[0].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        let moved = a.migrate(src, &b).unwrap();
+        assert!(moved
+            .iter()
+            .flatten()
+            .enumerate()
+            .all(|(i, byte)| *byte == i as u8));
+        assert!(b.contains_block(moved.as_ptr() as *const u8));
+        assert_eq!(a.live_block_count(), 0);
+
+        let empty = a.alloc_slice::<u64>(0).unwrap();
+        let empty = a.migrate(empty, &b).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(b.live_block_count(), 1);
+
+        let grown = b.realloc_slice(empty, 4).unwrap();
+        assert_eq!(&*grown, &[0; 4]);
+    }
+
+    #[test]
+    fn migrate_out_of_memory() {
+        if !crate::is_isolated() {
+            assert!(crate::run_isolated(
+                "allocator::tests::migrate_out_of_memory"
+            ));
+            return;
+        }
+
+        use crate::options::OptionsBuilder;
+
+        let a = Allocator::new(1, unsafe { mi_heap_new() });
+        let b = Allocator::new(2, unsafe { mi_heap_new() });
+
+        // too large for the memory already reserved from the OS
+        let src = a.alloc_slice_uninit::<u8>(3 << 29).unwrap();
+        let p = src.as_ptr();
+        OptionsBuilder::new().limit_os_alloc(true).build();
+
+        let src = a.migrate(src, &b).err().unwrap();
+        assert_eq!((src.as_ptr(), src.len()), (p, 3 << 29));
+        assert_eq!(b.live_block_count(), 0);
+
+        drop(src);
+        assert_eq!(a.live_block_count(), 0);
+    }
+
+    #[test]
+    fn alloc_slice_overflow() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        assert!(alloc.alloc_slice::<u64>(usize::MAX / 4).is_none());
+        assert!(alloc.alloc_slice_uninit::<u64>(usize::MAX / 4).is_none());
+    }
+
+    #[test]
+    fn alloc_slice_is_zeroed() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let mut slice = alloc.alloc_slice::<u32>(1024).unwrap();
+        assert_eq!(slice.len(), 1024);
+        assert!(slice.iter().all(|v| *v == 0));
+
+        slice[7] = 7;
+        assert_eq!(slice[7], 7);
+
+        let mut uninit = alloc.alloc_slice_uninit::<String>(4).unwrap();
+        assert_eq!(uninit.len(), 4);
+        uninit[0].write(String::from("written"));
+        unsafe { uninit[0].assume_init_drop() };
+    }
+
+    #[test]
+    fn realloc_array_checks_overflow() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        let p = alloc.mallocn(4, size_of::<u64>()) as *mut u64;
+        unsafe { p.copy_from_nonoverlapping([1, 2, 3, 4].as_ptr(), 4) };
+
+        assert!(alloc.realloc_array(p, usize::MAX / 4).is_none());
+        assert_eq!(unsafe { std::slice::from_raw_parts(p, 4) }, [1, 2, 3, 4]);
+        assert!(alloc.contains_block(p as *const u8));
+
+        let grown = alloc.realloc_array(p, 1024).unwrap().as_ptr();
+        assert_eq!(
+            unsafe { std::slice::from_raw_parts(grown, 4) },
+            [1, 2, 3, 4]
+        );
+
+        let shrunk = alloc.realloc_array(grown, 2).unwrap().as_ptr();
+        assert_eq!(unsafe { std::slice::from_raw_parts(shrunk, 2) }, [1, 2]);
+
+        alloc.free(shrunk as *mut u8);
+    }
+
+    #[test]
+    fn realloc_slice_raw_preserves_contents() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        let p = alloc.mallocn(4, size_of::<u32>()) as *mut u32;
+        assert!(!p.is_null());
+        for i in 0..4 {
+            unsafe { p.add(i).write(i as u32 * 10) };
+        }
+
+        let p = alloc.realloc_slice_raw(p, 4, 16);
+        assert!(!p.is_null());
+        for i in 0..4 {
+            assert_eq!(unsafe { p.add(i).read() }, i as u32 * 10);
+        }
+
+        assert!(alloc.realloc_slice_raw(p, 16, usize::MAX / 2).is_null());
+        assert_eq!(unsafe { p.add(3).read() }, 30);
+
+        alloc.free(p as *mut u8);
+    }
+
+    #[test]
+    fn alloc_static_is_writable_and_distinct() {
+        let alloc = Allocator::default();
+        let layout = Layout::from_size_align(256, 16).unwrap();
+
+        let a = alloc.alloc_static(layout);
+        let b = alloc.alloc_static(layout);
+        a.fill(0xaa);
+        b.fill(0xbb);
+
+        assert_eq!(a.len(), 256);
+        assert_eq!(a.as_ptr() as usize % 16, 0);
+        assert!(a.iter().all(|v| *v == 0xaa));
+        assert!(b.iter().all(|v| *v == 0xbb));
+        assert_ne!(a.as_ptr(), b.as_ptr());
+    }
+
+    #[test]
+    fn alloc_pinned_outlives_allocator() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let p = alloc
+            .alloc_pinned(Layout::from_size_align(128, 8).unwrap())
+            .unwrap();
+        unsafe { p.as_ptr().write_bytes(7, 128) };
+        drop(alloc);
+
+        let backing = unsafe { mi_heap_get_backing() };
+        assert!(unsafe { mi_heap_contains_block(backing, p.as_ptr() as *const c_void) });
+        assert_eq!(unsafe { p.as_ptr().add(127).read() }, 7);
+
+        unsafe { mi_free(p.as_ptr() as *mut c_void) };
+    }
+
+    #[test]
+    fn areas_cover_block_sizes() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let blocks: Vec<*mut u8> = [16, 128, 1024]
+            .iter()
+            .map(|size| alloc.malloc(*size))
+            .collect();
+
+        let areas = alloc.areas();
+        for size in [16, 128, 1024] {
+            let area = areas.iter().find(|area| area.block_size == size).unwrap();
+            assert!(area.used >= 1);
+            assert!(area.committed >= area.used_bytes());
+        }
+
+        for p in blocks {
+            alloc.free(p);
+        }
+    }
+
+    #[test]
+    fn snapshot_hash_is_deterministic() {
+        fn workload(sizes: &[usize]) -> u64 {
+            let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+            let blocks: Vec<*mut u8> = sizes.iter().map(|size| alloc.malloc(*size)).collect();
+            let hash = alloc.snapshot_hash();
+            for p in blocks {
+                alloc.free(p);
+            }
+            hash
+        }
+
+        assert_eq!(workload(&[8, 64, 64, 1000]), workload(&[8, 64, 64, 1000]));
+        assert_ne!(workload(&[8, 64, 64, 1000]), workload(&[8, 64, 128, 1000]));
+    }
+
+    #[test]
+    fn try_variants() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        let empty = alloc.try_malloc(0).unwrap();
+        let zeroed = alloc.try_zalloc(64).unwrap();
+        let counted = alloc.try_calloc(4, 16).unwrap();
+        let aligned = alloc.try_malloc_aligned(100, 64).unwrap();
+
+        assert!(unsafe { std::slice::from_raw_parts(zeroed.as_ptr(), 64) }
+            .iter()
+            .all(|v| *v == 0));
+        assert_eq!(aligned.as_ptr() as usize % 64, 0);
+        assert_eq!(alloc.live_block_count(), 4);
+
+        for p in [empty, zeroed, counted, aligned] {
+            alloc.free(p.as_ptr());
+        }
+        assert_eq!(alloc.live_block_count(), 0);
+    }
+
+    #[test]
+    fn expand_in_place() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let p = alloc.malloc(10);
+        unsafe { p.copy_from(b"0123456789".as_ptr(), 10) };
+
+        let usable = unsafe { mi::allocator::mi_usable_size(p as *const c_void) };
+        assert_eq!(alloc.try_expand(p, usable).map(NonNull::as_ptr), Some(p));
+        assert_eq!(unsafe { std::slice::from_raw_parts(p, 10) }, b"0123456789");
+
+        assert!(alloc.expand(p, 1 << 20).is_null());
+        assert_eq!(unsafe { std::slice::from_raw_parts(p, 10) }, b"0123456789");
+
+        alloc.free(p);
+    }
+
+    #[test]
+    fn grow_in_place_never_moves() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let p = alloc.malloc(unsafe { mi_good_size(64) });
+        unsafe { p.write_bytes(7, 64) };
+
+        let usable = unsafe { mi::allocator::mi_usable_size(p as *const c_void) };
+        assert!(alloc.grow_in_place(p, usable));
+        assert!(!alloc.grow_in_place(p, 1 << 30));
+        assert!(unsafe { std::slice::from_raw_parts(p, 64) }
+            .iter()
+            .all(|v| *v == 7));
+
+        alloc.free(p);
+    }
+
+    #[test]
+    fn live_bytes_counts_blocks() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let blocks: Vec<*mut u8> = (0..100).map(|_| alloc.malloc(64)).collect();
+
+        let usage = alloc.live_bytes();
+        assert!(usage.used >= 6400);
+        assert!(usage.block_count >= 100);
+        assert!(usage.committed >= usage.used);
+        assert!(usage.reserved >= usage.committed);
+        assert_eq!(usage.block_count, alloc.live_block_count());
+
+        for p in blocks {
+            alloc.free(p);
+        }
+    }
+
+    #[test]
+    fn layout_alloc_and_dealloc() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        let empty = Layout::from_size_align(0, 32).unwrap();
+        let p = alloc.alloc_layout(empty);
+        assert!(!p.is_null());
+        assert_eq!(p as usize % 32, 0);
+        assert_eq!(alloc.live_block_count(), 0);
+        alloc.dealloc_layout(p, empty);
+
+        let aligned = Layout::from_size_align(200, 64).unwrap();
+        let p = alloc.alloc_layout(aligned);
+        assert!(!p.is_null());
+        assert_eq!(p as usize % 64, 0);
+        unsafe { p.write_bytes(1, 200) };
+        alloc.dealloc_layout(p, aligned);
+        assert_eq!(alloc.live_block_count(), 0);
+    }
+
+    #[test]
+    fn default_heap_guard_restores() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let previous = unsafe { mi_heap_get_default() };
+
+        {
+            let _guard = alloc.as_default();
+            assert_eq!(unsafe { mi_heap_get_default() }, alloc.heap());
+
+            let p = unsafe { mi::allocator::mi_malloc(64) };
+            assert!(alloc.contains_block(p as *const u8));
+            unsafe { mi_free(p) };
+        }
+
+        assert_eq!(unsafe { mi_heap_get_default() }, previous);
+    }
+
+    #[test]
+    fn checked_count_allocations() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        assert_eq!(alloc.try_calloc(usize::MAX, 2), Err(AllocError::Overflow));
+        assert_eq!(alloc.try_mallocn(usize::MAX, 2), Err(AllocError::Overflow));
+
+        let zeroed = alloc.try_calloc(16, 8).unwrap();
+        let uninit = alloc.try_mallocn(16, 8).unwrap();
+        let bytes = unsafe { std::slice::from_raw_parts(zeroed.as_ptr(), 128) };
+        assert!(bytes.iter().all(|v| *v == 0));
+
+        alloc.free(zeroed.as_ptr());
+        alloc.free(uninit.as_ptr());
+    }
+
+    #[test]
+    fn foreign_pointers() {
+        let alloc = Allocator::default();
+        let p = alloc.malloc(64);
+        let on_stack = 0u64;
+        let stack = &on_stack as *const u64 as *const u8;
+
+        assert!(is_in_heap_region(p));
+        assert!(!is_in_heap_region(stack));
+        assert!(check_owned_by_default(p));
+        assert!(!check_owned_by_default(stack));
+
+        alloc.free(p);
+    }
+
+    #[test]
+    fn duplicate_heap_is_separate() {
+        let mut pool = crate::AllocatorPool::new();
+        pool.set_alloc_policy(AllocPolicy::Abort);
+        let first = pool.new_named_allocator("source");
+        pool.set_alloc_policy(AllocPolicy::ReturnNull);
+        let dup = pool.duplicate_heap(first.id()).unwrap();
+
+        assert!(!dup.is_same_heap(first.heap()));
+        assert_ne!(first.id(), dup.id());
+        assert_eq!(dup.policy(), AllocPolicy::Abort);
+        assert_eq!(dup.name(), Some("source"));
+
+        let p = dup.malloc(64);
+        assert!(dup.contains_block(p));
+        assert!(!first.contains_block(p));
+        dup.free(p);
+
+        assert!(pool.duplicate_heap(100).is_none());
+    }
+
+    #[test]
+    fn alloc_box() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let mut b = alloc.alloc_box(String::from("cesium")).unwrap();
+        b.push_str("-box");
+
+        assert_eq!(*b, "cesium-box");
+        assert!(alloc.contains_block(b.as_ptr() as *const u8));
+        drop(b);
+        assert_eq!(alloc.live_block_count(), 0);
+    }
+
+    #[test]
+    fn alloc_zeroed_box() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let b = alloc.alloc_zeroed_box::<[u64; 512]>().unwrap();
+
+        assert!(b.iter().all(|v| *v == 0));
+        assert_eq!(b.as_ptr() as usize % align_of::<u64>(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "bytemuck")]
+    fn bytemuck_types_are_zeroable() {
+        #[derive(Clone, Copy)]
+        struct Pair {
+            a: u32,
+            b: f32,
+        }
+
+        unsafe impl bytemuck::Zeroable for Pair {}
+
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let b = alloc.alloc_zeroed_box_bytemuck::<Pair>().unwrap();
+        assert_eq!((b.a, b.b), (0, 0.0));
+
+        let slice = alloc.alloc_slice_bytemuck::<Pair>(16).unwrap();
+        assert!(slice.iter().all(|pair| pair.a == 0 && pair.b == 0.0));
+    }
+
+    #[test]
+    fn alloc_slice_rounded() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let (slice, capacity) = alloc.alloc_slice_rounded::<u32>(100).unwrap();
+        let usable = unsafe { mi::allocator::mi_usable_size(slice.as_ptr() as *const c_void) };
+
+        assert_eq!(slice.len(), 100);
+        assert!(capacity >= 100);
+        assert_eq!(capacity, usable / 4);
+    }
+
+    #[test]
+    fn realloc_slice_grow_and_shrink() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let mut slice = alloc.alloc_slice::<u64>(4).unwrap();
+        slice.copy_from_slice(&[1, 2, 3, 4]);
+
+        let slice = alloc.realloc_slice(slice, 1024).unwrap();
+        assert_eq!(&slice[..4], &[1, 2, 3, 4]);
+        assert!(slice[4..].iter().all(|v| *v == 0));
+
+        let slice = alloc.realloc_slice(slice, 2).unwrap();
+        assert_eq!(&*slice, &[1, 2]);
+    }
+
+    #[test]
+    fn realloc_slice_uninit_keeps_prefix() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let mut slice = alloc.alloc_slice_uninit::<String>(2).unwrap();
+        slice[0].write(String::from("a"));
+        slice[1].write(String::from("b"));
+
+        let slice = alloc.realloc_slice_uninit(slice, 64).unwrap();
+        assert_eq!(slice.len(), 64);
+        let mut slice = alloc.realloc_slice_uninit(slice, 2).unwrap();
+        assert_eq!(unsafe { slice[1].assume_init_ref() }, "b");
+
+        for s in slice.iter_mut() {
+            unsafe { s.assume_init_drop() };
+        }
+    }
+
+    #[test]
+    fn realloc_slice_overflow() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let mut slice = alloc.alloc_slice::<u64>(4).unwrap();
+        slice.copy_from_slice(&[1, 2, 3, 4]);
+        let p = slice.as_ptr();
+
+        let slice = alloc.realloc_slice(slice, usize::MAX).unwrap_err();
+        assert_eq!(slice.as_ptr(), p);
+        assert_eq!(&*slice, &[1, 2, 3, 4]);
+        assert_eq!(alloc.live_block_count(), 1);
+
+        drop(slice);
+        assert_eq!(alloc.live_block_count(), 0);
+    }
+
+    #[test]
+    fn malloc_usable_size_matches() {
+        let alloc = Allocator::default();
+        let p = alloc.malloc(100);
+
+        let usable = unsafe { mi::allocator::mi_usable_size(p as *const c_void) };
+        assert_eq!(malloc_usable_size(p), usable);
+        assert_eq!(malloc_usable_size(std::ptr::null()), 0);
+
+        alloc.free(p);
+    }
+
+    fn limited_alloc(policy: AllocPolicy) -> *mut u8 {
+        use crate::options::OptionsBuilder;
+
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() }).with_policy(policy);
+        OptionsBuilder::new().limit_os_alloc(true).build();
+        alloc.malloc(1 << 30)
+    }
+
+    #[test]
+    fn return_null_policy() {
+        if crate::is_isolated() {
+            assert!(limited_alloc(AllocPolicy::ReturnNull).is_null());
+            return;
+        }
+
+        assert_eq!(Allocator::default().policy(), AllocPolicy::ReturnNull);
+        assert!(crate::run_isolated("allocator::tests::return_null_policy"));
+    }
+
+    #[test]
+    fn abort_policy() {
+        if crate::is_isolated() {
+            limited_alloc(AllocPolicy::Abort);
+            return;
+        }
+
+        assert!(!crate::run_isolated("allocator::tests::abort_policy"));
+    }
+
+    #[test]
+    fn dup_cstr() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let original = c"cesium allocator";
+
+        let copy = alloc.dup_cstr(original).unwrap();
+        assert_eq!(&*copy, original);
+        assert_ne!(copy.as_ptr(), original.as_ptr());
+        assert!(alloc.contains_block(copy.as_ptr() as *const u8));
+
+        let prefix = alloc.dup_cstr_n(original, 6).unwrap();
+        assert_eq!(prefix.to_bytes(), b"cesium");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn resolve_path() {
+        let alloc = Allocator::default();
+
+        let root = alloc.resolve_path(c"/").unwrap();
+        assert_eq!(root.to_bytes(), b"/");
+
+        let err = alloc.resolve_path(c"/does/not/exist/cesium").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn debug_format() {
+        let alloc = Allocator::new(42, unsafe { mi_heap_new() });
+        let text = format!("{:?}", alloc);
+
+        assert!(text.contains("id: 42"));
+        assert!(text.contains(&format!("{:p}", alloc.heap())));
+        assert!(text.contains("0x"));
+    }
+
+    #[test]
+    fn destroy_frees_everything() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        for _ in 0..1000 {
+            assert!(!alloc.malloc(128).is_null());
+        }
+        assert_eq!(alloc.live_block_count(), 1000);
+
+        unsafe { alloc.destroy() };
+    }
+
+    #[test]
+    #[should_panic(expected = "only an owned heap can be destroyed")]
+    fn destroy_default_heap_panics() {
+        unsafe { Allocator::default().destroy() };
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let alloc = Allocator::new(3, unsafe { mi_heap_new() });
+        let p = alloc.malloc(64);
+        let heap = alloc.into_raw();
+
+        let alloc = unsafe { Allocator::from_raw(3, heap) };
+        assert_eq!(alloc.id(), 3);
+        assert!(alloc.contains_block(p));
+
+        let q = alloc.malloc(64);
+        assert!(!q.is_null());
+        alloc.free(p);
+        alloc.free(q);
+    }
+
+    #[test]
+    fn aligned_validation() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        assert_eq!(
+            alloc.try_malloc_aligned(64, 3),
+            Err(AllocError::InvalidAlignment)
+        );
+        assert_eq!(
+            alloc.try_malloc_aligned(64, 0),
+            Err(AllocError::InvalidAlignment)
+        );
+        assert_eq!(
+            alloc.try_malloc_aligned_at(64, 24, 8),
+            Err(AllocError::InvalidAlignment)
+        );
+
+        let p = alloc.try_malloc_aligned(100, 64).unwrap();
+        assert_eq!(p.as_ptr() as usize % 64, 0);
+        let q = alloc.try_malloc_aligned_at(100, 64, 16).unwrap();
+        assert_eq!((q.as_ptr() as usize + 16) % 64, 0);
+
+        alloc.free(p.as_ptr());
+        alloc.free(q.as_ptr());
+    }
+
+    #[test]
+    fn posix_memalign() {
+        let alloc = Allocator::default();
+
+        let p = alloc.posix_memalign(16, 100).unwrap();
+        assert_eq!(p.as_ptr() as usize % 16, 0);
+        alloc.free(p.as_ptr());
+
+        assert_eq!(alloc.posix_memalign(12, 100), Err(libc::EINVAL));
+        assert_eq!(alloc.posix_memalign(2, 100), Err(libc::EINVAL));
+    }
+
+    #[test]
+    fn reset_starts_from_baseline() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let before = alloc.heap();
+        let kept: Vec<_> = (0..100).map(|_| alloc.malloc(512)).collect();
+        assert!(alloc.live_bytes().used >= 100 * 512);
+
+        alloc.reset();
+        assert_ne!(alloc.heap(), before);
+        assert_eq!(alloc.live_bytes(), HeapUsage::default());
+
+        let p = alloc.malloc(512);
+        assert!(alloc.contains_block(p));
+        alloc.free(p);
+        for p in kept {
+            alloc.free(p);
+        }
+    }
+
+    #[test]
+    fn backing_outlives_allocator() {
+        let backing = Allocator::backing();
+        assert_eq!(backing.heap(), unsafe { mi_heap_get_backing() });
+        let p = backing.malloc(64);
+        assert!(backing.contains_block(p));
+        drop(backing);
+
+        let backing = Allocator::backing();
+        let q = backing.malloc(64);
+        assert!(!q.is_null());
+        assert!(backing.contains_block(p));
+
+        backing.free(p);
+        backing.free(q);
+    }
+
+    #[test]
+    fn allocation_closures() {
+        fn with_closures<A, D>(alloc: A, dealloc: D) -> usize
+        where
+            A: Fn(Layout) -> *mut u8,
+            D: Fn(*mut u8, Layout), {
+            let layout = Layout::from_size_align(256, 16).unwrap();
+            let p = alloc(layout);
+            assert!(!p.is_null());
+            assert_eq!(p as usize % 16, 0);
+            unsafe { p.write_bytes(1, 256) };
+            let usable = malloc_usable_size(p);
+            dealloc(p, layout);
+            usable
+        }
+
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        assert!(with_closures(alloc.alloc_fn(), alloc.dealloc_fn()) >= 256);
+        assert_eq!(alloc.live_block_count(), 0);
+    }
+
+    #[test]
+    fn equal_by_id() {
+        use std::{
+            collections::{
+                hash_map::DefaultHasher,
+                HashSet,
+            },
+            sync::Arc,
+        };
+
+        fn hash_of(alloc: &Allocator) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            alloc.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let a = Arc::new(Allocator::new(1, unsafe { mi_heap_new() }));
+        let b = Arc::new(Allocator::new(2, unsafe { mi_heap_new() }));
+
+        // the heap is atomic, but only the id is hashed
+        #[allow(clippy::mutable_key_type)]
+        let set: HashSet<_> = [a.clone(), a.clone()].into_iter().collect();
+        assert_eq!(set.len(), 1);
+
+        assert_ne!(*a, *b);
+        assert_ne!(hash_of(&a), hash_of(&b));
+        assert_eq!(*a, Allocator::new(1, unsafe { mi_heap_new() }));
+    }
+
+    #[test]
+    fn abandoned_blocks_stay_valid() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let p = alloc.malloc(64);
+        unsafe { p.write_bytes(0xab, 64) };
+
+        alloc.abandon();
+
+        let bytes = unsafe { std::slice::from_raw_parts(p, 64) };
+        assert!(bytes.iter().all(|b| *b == 0xab));
+        assert!(check_owned_by_default(p));
+        unsafe { mi_free(p as *mut c_void) };
+    }
+
+    #[test]
+    fn header_before_aligned_payload() {
+        #[allow(dead_code)]
+        struct Header {
+            len: u32,
+            tag: u8,
+        }
+
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        for payload_align in [1, 16, 64, 4096] {
+            let (header, payload) = alloc
+                .alloc_with_header::<Header, [u64; 8]>(payload_align)
+                .unwrap();
+            assert_eq!(payload as usize % payload_align, 0);
+            assert_eq!(payload as usize % align_of::<u64>(), 0);
+            assert_eq!(header as usize % align_of::<Header>(), 0);
+            assert!(header as usize + size_of::<Header>() <= payload as usize);
+
+            unsafe {
+                header.write(Header { len: 8, tag: 1 });
+                payload.write([7; 8]);
+            }
+            alloc.free(header as *mut u8);
+        }
+
+        assert!(alloc.alloc_with_header::<Header, u64>(48).is_none());
+    }
+
+    #[test]
+    fn idle_collection_frees_empty_pages() {
+        if !crate::is_isolated() {
+            assert!(crate::run_isolated(
+                "allocator::tests::idle_collection_frees_empty_pages"
+            ));
+            return;
+        }
+
+        let alloc = Arc::new(Allocator::new(1, unsafe { mi_heap_new() }));
+        let blocks: Vec<_> = (0..10_000).map(|_| alloc.malloc(64)).collect();
+        for p in blocks {
+            alloc.free(p);
+        }
+        // mimalloc holds on to the last empty page until the heap is collected
+        assert!(!alloc.areas().is_empty());
+
+        alloc.enable_idle_collection(4);
+        let other = Allocator::default();
+        let blocks: Vec<_> = (0..100_000).map(|_| other.malloc(64)).collect();
+        Allocator::disable_idle_collection();
+        for p in blocks {
+            other.free(p);
+        }
+
+        assert!(alloc.areas().is_empty());
+    }
+
+    #[test]
+    fn over_aligned_box() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        let boxed = alloc.alloc_box_aligned(7u32, 128).unwrap();
+        assert_eq!(boxed.as_ptr() as usize % 128, 0);
+        assert_eq!(*boxed, 7);
+        drop(boxed);
+
+        let unit = alloc.alloc_box_aligned((), 64).unwrap();
+        assert_eq!(unit.as_ptr() as usize % 64, 0);
+        drop(unit);
+
+        assert!(alloc.alloc_box_aligned(7u32, 96).is_none());
+        assert_eq!(alloc.live_block_count(), 0);
+    }
+
+    #[test]
+    fn zero_sized_types_are_not_allocated() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        let unit = alloc.alloc_box(()).unwrap();
+        assert_eq!(unit.as_ptr(), NonNull::dangling().as_ptr());
+
+        let slice = alloc.alloc_slice::<[u8; 0]>(100).unwrap();
+        assert_eq!(slice.len(), 100);
+        assert_eq!(slice.as_ptr(), NonNull::dangling().as_ptr());
+
+        let (rounded, capacity) = alloc.alloc_slice_rounded::<()>(3).unwrap();
+        assert_eq!(capacity, usize::MAX);
+        let resized = alloc.realloc_slice(rounded, 10).unwrap();
+        assert_eq!(resized.len(), 10);
+
+        assert_eq!(alloc.live_block_count(), 0);
+        drop((unit, slice, resized));
+        assert_eq!(alloc.live_block_count(), 0);
+    }
+
+    #[test]
+    fn snapshot_live_blocks() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let sizes = [24, 100, 1000];
+        let blocks: Vec<_> = sizes.iter().map(|size| alloc.malloc(*size)).collect();
+
+        let snapshot = alloc.snapshot_blocks();
+        assert_eq!(snapshot.len(), 3);
+        for (p, size) in blocks.iter().zip(sizes) {
+            let block = snapshot.iter().find(|block| block.ptr == *p).unwrap();
+            assert!(block.size >= size);
+            assert!(block.block_size >= size);
+        }
+
+        for p in blocks {
+            alloc.free(p);
+        }
+        assert!(alloc.snapshot_blocks().is_empty());
+    }
+
+    #[test]
+    fn warmup_then_allocate() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        alloc.warmup(1 << 20);
+        assert_eq!(alloc.live_block_count(), 0);
+
+        let p = alloc.try_malloc(1 << 20).unwrap();
+        alloc.free(p.as_ptr());
+
+        alloc.warmup(0);
+    }
+
+    #[test]
+    fn fragmentation_after_interleaved_frees() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        assert_eq!(alloc.fragmentation(), 0.0);
+
+        let blocks: Vec<_> = (0..1000).map(|_| alloc.malloc(64)).collect();
+        for p in blocks.iter().step_by(2) {
+            alloc.free(*p);
+        }
+
+        let ratio = alloc.fragmentation();
+        assert!(ratio > 0.0 && ratio <= 1.0, "{ratio}");
+
+        for p in blocks.iter().skip(1).step_by(2) {
+            alloc.free(*p);
+        }
+    }
+
+    #[test]
+    fn collect_into_fixed_capacity() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        let slice = alloc.collect_into((1..=5).map(|i| i * 10), 8).unwrap();
+        assert_eq!(&*slice, &[10, 20, 30, 40, 50]);
+        let usable = malloc_usable_size(slice.as_ptr() as *const u8);
+        assert!(usable / size_of::<i32>() >= 8);
+        drop(slice);
+
+        let slice = alloc.collect_into(0u64.., 4).unwrap();
+        assert_eq!(&*slice, &[0, 1, 2, 3]);
+        drop(slice);
+
+        assert_eq!(alloc.live_block_count(), 0);
+    }
+
+    #[test]
+    fn free_with_layout() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        let layout = Layout::from_size_align(100, 32).unwrap();
+        let p = alloc.malloc_aligned(layout.size(), layout.align());
+        assert!(!p.is_null());
+        alloc.free_sized(p, layout);
+
+        let layout = Layout::new::<u64>();
+        let p = alloc.malloc(layout.size());
+        alloc.free_sized(p, layout);
+
+        assert_eq!(alloc.live_block_count(), 0);
+    }
+
+    #[test]
+    fn histogram_per_size_class() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        let small: Vec<_> = (0..10).map(|_| alloc.malloc(32)).collect();
+        let large: Vec<_> = (0..5).map(|_| alloc.malloc(256)).collect();
+
+        let histogram = alloc.block_histogram();
+        assert_eq!(histogram.get(&unsafe { mi_good_size(32) }), Some(&10));
+        assert_eq!(histogram.get(&unsafe { mi_good_size(256) }), Some(&5));
+        assert_eq!(histogram.values().sum::<usize>(), 15);
+
+        for p in small.into_iter().chain(large) {
+            alloc.free(p);
+        }
+        assert!(alloc.block_histogram().is_empty());
+    }
+
+    #[test]
+    fn place_with_frees_on_panic() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+
+        let placed = alloc.place_with(|| [7u64; 32]).unwrap();
+        assert_eq!(*placed, [7; 32]);
+        assert_eq!(*alloc.place(5u8).unwrap(), 5);
+        drop(placed);
+        assert_eq!(alloc.live_block_count(), 0);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            alloc.place_with::<[u64; 32], _>(|| panic!("expensive value failed"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(alloc.live_block_count(), 0);
+    }
+
+    #[test]
+    fn aligned_invariants() {
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        alloc.verify_aligned_invariants();
     }
 }
@@ -0,0 +1,83 @@
+use cesium_libmimalloc_sys::heap::mi_heap_area_t;
+
+/// A summary of a heap area, which contains blocks of a single size.
+///
+/// See [`Allocator::areas`](crate::allocator::Allocator::areas).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AreaInfo {
+    /// Bytes reserved for this area.
+    pub reserved: usize,
+    /// Current committed bytes of this area.
+    pub committed: usize,
+    /// Number of blocks in use in this area.
+    pub used: usize,
+    /// Size in bytes of one block.
+    pub block_size: usize,
+    /// Size in bytes of a full block including padding and metadata.
+    pub full_block_size: usize,
+}
+
+impl AreaInfo {
+    /// Bytes in use by allocated blocks in this area.
+    pub fn used_bytes(&self) -> usize {
+        self.used * self.block_size
+    }
+}
+
+impl From<&mi_heap_area_t> for AreaInfo {
+    fn from(area: &mi_heap_area_t) -> Self {
+        AreaInfo {
+            reserved: area.reserved,
+            committed: area.committed,
+            used: area.used,
+            block_size: area.block_size,
+            full_block_size: area.full_block_size,
+        }
+    }
+}
+
+/// A live block in a heap, at the time it was visited.
+///
+/// See [`Allocator::snapshot_blocks`](crate::allocator::Allocator::snapshot_blocks).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRef {
+    /// Start of the block.
+    pub ptr: *mut u8,
+    /// Usable size in bytes of the block.
+    pub size: usize,
+    /// Size in bytes of the blocks in the block's area.
+    pub block_size: usize,
+}
+
+/// Memory held by a heap, summed over its areas.
+///
+/// See [`Allocator::live_bytes`](crate::allocator::Allocator::live_bytes).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HeapUsage {
+    /// Bytes in use by allocated blocks.
+    pub used: usize,
+    /// Bytes committed.
+    pub committed: usize,
+    /// Bytes reserved.
+    pub reserved: usize,
+    /// Number of allocated blocks.
+    pub block_count: usize,
+}
+
+impl HeapUsage {
+    /// Add an area to the totals.
+    pub fn add_area(&mut self, area: &AreaInfo) {
+        self.used += area.used_bytes();
+        self.committed += area.committed;
+        self.reserved += area.reserved;
+        self.block_count += area.used;
+    }
+
+    /// Add the totals of another heap.
+    pub fn add_usage(&mut self, other: &HeapUsage) {
+        self.used += other.used;
+        self.committed += other.committed;
+        self.reserved += other.reserved;
+        self.block_count += other.block_count;
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    ops::{
+        Deref,
+        DerefMut,
+    },
+    ptr::{
+        self,
+        NonNull,
+    },
+};
+
+use crate::allocator::Allocator;
+
+/// A single `T` allocated out of an [`Allocator`].
+///
+/// Derefs to `T`, and drops the value and frees the allocation when dropped.
+/// Zero-sized types aren't allocated at all.
+pub struct HeapBox<'a, T> {
+    ptr: NonNull<T>,
+    alloc: &'a Allocator,
+}
+
+impl<'a, T> HeapBox<'a, T> {
+    /// Take ownership of the initialized value at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by `alloc`, be aligned for `T` and hold
+    /// an initialized value of `T`.
+    pub(crate) unsafe fn from_raw(alloc: &'a Allocator, ptr: NonNull<T>) -> Self {
+        HeapBox { ptr, alloc }
+    }
+
+    /// The allocator this box was allocated from.
+    pub fn allocator(&self) -> &'a Allocator {
+        self.alloc
+    }
+
+    /// A raw pointer to the value.
+    pub fn as_ptr(&self) -> *const T {
+        self.ptr.as_ptr()
+    }
+
+    /// A raw mutable pointer to the value.
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+}
+
+impl<T> Deref for HeapBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for HeapBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for HeapBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.ptr.as_ptr()) };
+        self.alloc.free_array(self.ptr);
+    }
+}
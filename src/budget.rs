@@ -0,0 +1,179 @@
+use std::{
+    ffi::c_void,
+    ptr,
+    sync::atomic::{
+        AtomicUsize,
+        Ordering,
+    },
+};
+
+use cesium_libmimalloc_sys::allocator::mi_usable_size;
+
+use crate::allocator::Allocator;
+
+/// An [`Allocator`] with a soft cap on the bytes it keeps alive, e.g. for a
+/// plugin sandbox.
+///
+/// Allocations that would take the live bytes past `max_bytes` return null.
+/// Blocks are charged at their usable size, as reported by `mi_usable_size`,
+/// which may be a bit more than was requested. Blocks allocated through the
+/// budget must be freed through it too, or the accounting drifts.
+pub struct BudgetAllocator {
+    inner: Allocator,
+    budget: Budget,
+}
+
+/// Live bytes charged against a cap.
+///
+/// Shared by [`BudgetAllocator`] and
+/// [`FallbackAllocator`](crate::fallback::FallbackAllocator).
+pub(crate) struct Budget {
+    live: AtomicUsize,
+    max_bytes: usize,
+}
+
+impl Budget {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Budget {
+            live: AtomicUsize::new(0),
+            max_bytes,
+        }
+    }
+
+    pub(crate) fn live(&self) -> usize {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    /// Whether `size` more bytes would fit right now.
+    pub(crate) fn fits(&self, size: usize) -> bool {
+        self.live()
+            .checked_add(size)
+            .is_some_and(|live| live <= self.max_bytes)
+    }
+
+    /// Charge `size` bytes, or nothing and return `false` if they don't fit.
+    ///
+    /// The check and the charge are a single atomic update, so concurrent
+    /// charges can't overshoot the cap together.
+    pub(crate) fn charge(&self, size: usize) -> bool {
+        self.live
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |live| {
+                live.checked_add(size)
+                    .filter(|live| *live <= self.max_bytes)
+            })
+            .is_ok()
+    }
+
+    /// Give `size` charged bytes back.
+    pub(crate) fn release(&self, size: usize) {
+        self.live.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+impl BudgetAllocator {
+    /// Cap the live bytes allocated from `inner` at `max_bytes`.
+    pub fn new(inner: Allocator, max_bytes: usize) -> Self {
+        BudgetAllocator {
+            inner,
+            budget: Budget::new(max_bytes),
+        }
+    }
+
+    /// The allocator doing the work. Calls made directly on it aren't charged.
+    pub fn inner(&self) -> &Allocator {
+        &self.inner
+    }
+
+    /// The cap on live bytes.
+    pub fn max_bytes(&self) -> usize {
+        self.budget.max_bytes
+    }
+
+    /// The bytes currently charged to the budget.
+    pub fn live_bytes(&self) -> usize {
+        self.budget.live()
+    }
+
+    /// Allocate `size` bytes, see [`Allocator::malloc`].
+    ///
+    /// Returns null if the block would exceed the budget or on out-of-memory.
+    pub fn malloc(&self, size: usize) -> *mut u8 {
+        if !self.budget.fits(size) {
+            return ptr::null_mut();
+        }
+
+        self.charge(self.inner.malloc(size))
+    }
+
+    /// Allocate `count` zeroed items of `size` bytes, see
+    /// [`Allocator::calloc`].
+    ///
+    /// Returns null if `count * size` overflows, if the block would exceed the
+    /// budget, or on out-of-memory.
+    pub fn calloc(&self, count: usize, size: usize) -> *mut u8 {
+        match count.checked_mul(size) {
+            | Some(total) if self.budget.fits(total) => self.charge(self.inner.calloc(count, size)),
+            | _ => ptr::null_mut(),
+        }
+    }
+
+    /// Free `p`, which must have been allocated through this budget, and give
+    /// its bytes back.
+    pub fn free(&self, p: *mut u8) {
+        if p.is_null() {
+            return;
+        }
+
+        self.budget
+            .release(unsafe { mi_usable_size(p as *const c_void) });
+        self.inner.free(p);
+    }
+
+    /// Charge the block at `p` to the budget, freeing it instead if it doesn't
+    /// fit, e.g. because of a concurrent allocation.
+    fn charge(&self, p: *mut u8) -> *mut u8 {
+        if p.is_null() {
+            return p;
+        }
+
+        if !self
+            .budget
+            .charge(unsafe { mi_usable_size(p as *const c_void) })
+        {
+            self.inner.free(p);
+            return ptr::null_mut();
+        }
+        p
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cesium_libmimalloc_sys::heap::mi_heap_new;
+
+    use super::*;
+
+    #[test]
+    fn caps_live_bytes() {
+        let budget = BudgetAllocator::new(Allocator::new(1, unsafe { mi_heap_new() }), 1024);
+
+        let a = budget.malloc(512);
+        let b = budget.malloc(512);
+        assert!(!a.is_null() && !b.is_null());
+        assert_eq!(budget.live_bytes(), 1024);
+
+        assert!(budget.malloc(512).is_null());
+        assert!(budget.calloc(64, 8).is_null());
+        assert!(budget.calloc(usize::MAX, 2).is_null());
+        assert_eq!(budget.inner().live_block_count(), 2);
+
+        budget.free(a);
+        assert_eq!(budget.live_bytes(), 512);
+        let c = budget.calloc(64, 8);
+        assert!(!c.is_null());
+
+        budget.free(b);
+        budget.free(c);
+        assert_eq!(budget.live_bytes(), 0);
+    }
+}
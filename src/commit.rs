@@ -0,0 +1,161 @@
+#[cfg(feature = "commit-watcher")]
+use std::{
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        Arc,
+    },
+    thread::{
+        self,
+        JoinHandle,
+    },
+    time::Duration,
+};
+
+use crate::stats::process_info;
+
+/// Watches the process' committed memory and calls back when it drops.
+///
+/// Each [`tick`](CommitWatcher::tick) samples `current_commit` from
+/// [`process_info`] and compares it with the previous sample. A drop of more
+/// than `threshold` bytes means mimalloc returned memory to the OS (e.g. after
+/// a purge or a forced collect), and `on_drop` is called with the previous and
+/// current commit.
+///
+/// Ticks are manual by default; with the `commit-watcher` feature the watcher
+/// can also be [spawned](CommitWatcher::spawn) onto a background thread.
+pub struct CommitWatcher {
+    threshold: usize,
+    last_commit: usize,
+    on_drop: Box<dyn FnMut(usize, usize) + Send>,
+}
+
+impl CommitWatcher {
+    /// Create a watcher, taking the first sample immediately.
+    pub fn new<F>(threshold: usize, on_drop: F) -> Self
+    where
+        F: FnMut(usize, usize) + Send + 'static, {
+        CommitWatcher {
+            threshold,
+            last_commit: process_info().current_commit,
+            on_drop: Box::new(on_drop),
+        }
+    }
+
+    /// Take a sample, calling `on_drop` if the commit dropped by more than the
+    /// threshold since the previous sample.
+    ///
+    /// Returns whether the callback was called.
+    pub fn tick(&mut self) -> bool {
+        let previous = self.last_commit;
+        let current = process_info().current_commit;
+        self.last_commit = current;
+
+        let dropped = previous.saturating_sub(current) > self.threshold;
+        if dropped {
+            (self.on_drop)(previous, current);
+        }
+
+        dropped
+    }
+
+    /// The committed memory at the last sample.
+    pub fn last_commit(&self) -> usize {
+        self.last_commit
+    }
+
+    /// Move the watcher onto a background thread which ticks every `interval`.
+    ///
+    /// The thread runs until the returned handle is stopped or dropped.
+    #[cfg(feature = "commit-watcher")]
+    pub fn spawn(mut self, interval: Duration) -> CommitWatcherHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Acquire) {
+                    self.tick();
+                    thread::park_timeout(interval);
+                }
+                self
+            })
+        };
+
+        CommitWatcherHandle {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+/// Handle to a [`CommitWatcher`] running on a background thread.
+#[cfg(feature = "commit-watcher")]
+pub struct CommitWatcherHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<CommitWatcher>>,
+}
+
+#[cfg(feature = "commit-watcher")]
+impl CommitWatcherHandle {
+    /// Stop the background thread and hand the watcher back.
+    pub fn stop(mut self) -> CommitWatcher {
+        self.join().expect("commit watcher thread panicked")
+    }
+
+    fn join(&mut self) -> Option<CommitWatcher> {
+        let thread = self.thread.take()?;
+        self.stop.store(true, Ordering::Release);
+        thread.thread().unpark();
+        thread.join().ok()
+    }
+}
+
+#[cfg(feature = "commit-watcher")]
+impl Drop for CommitWatcherHandle {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+    };
+
+    use cesium_libmimalloc_sys::heap::mi_heap_new;
+
+    use super::*;
+    use crate::allocator::Allocator;
+
+    #[test]
+    fn free_and_collect_triggers_drop() {
+        const SIZE: usize = 64 << 20;
+
+        let alloc = Allocator::new(1, unsafe { mi_heap_new() });
+        let p = alloc.malloc(SIZE);
+        assert!(!p.is_null());
+        unsafe { p.write_bytes(1, SIZE) };
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut watcher = {
+            let drops = drops.clone();
+            CommitWatcher::new(SIZE / 2, move |previous, current| {
+                assert!(previous > current);
+                drops.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+
+        alloc.free(p);
+        alloc.collect(true);
+
+        assert!(watcher.tick());
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,53 @@
+use std::{
+    ffi::{
+        c_char,
+        CStr,
+    },
+    ops::Deref,
+    ptr::NonNull,
+};
+
+use crate::allocator::Allocator;
+
+/// A nul-terminated C string allocated out of an [`Allocator`].
+///
+/// Derefs to `CStr`, and frees the allocation when dropped.
+pub struct HeapCStr<'a> {
+    ptr: NonNull<c_char>,
+    alloc: &'a Allocator,
+}
+
+impl<'a> HeapCStr<'a> {
+    /// Take ownership of the C string at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a nul-terminated string allocated by mimalloc.
+    pub(crate) unsafe fn from_raw(alloc: &'a Allocator, ptr: NonNull<c_char>) -> Self {
+        HeapCStr { ptr, alloc }
+    }
+
+    /// The allocator this string was allocated from.
+    pub fn allocator(&self) -> &'a Allocator {
+        self.alloc
+    }
+
+    /// A raw pointer to the string.
+    pub fn as_ptr(&self) -> *const c_char {
+        self.ptr.as_ptr()
+    }
+}
+
+impl Deref for HeapCStr<'_> {
+    type Target = CStr;
+
+    fn deref(&self) -> &CStr {
+        unsafe { CStr::from_ptr(self.ptr.as_ptr()) }
+    }
+}
+
+impl Drop for HeapCStr<'_> {
+    fn drop(&mut self) {
+        self.alloc.free(self.ptr.as_ptr() as *mut u8);
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    error::Error,
+    fmt,
+};
+
+use crate::options::OptionName;
+
+/// Returned when a pool id is already bound to an allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdInUse(pub u32);
+
+impl fmt::Display for IdInUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "allocator id {} is already in use", self.0)
+    }
+}
+
+impl Error for IdInUse {}
+
+/// Returned when a boolean option is set as a number, or a numeric option as a
+/// boolean.
+///
+/// See [`set_numeric`](crate::options::set_numeric) and
+/// [`set_enabled`](crate::options::set_enabled).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WrongOptionKind(pub OptionName);
+
+impl fmt::Display for WrongOptionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if self.0.is_boolean() {
+            "boolean"
+        } else {
+            "numeric"
+        };
+        write!(f, "option {:?} is {}", self.0, kind)
+    }
+}
+
+impl Error for WrongOptionKind {}
+
+/// An error reported by mimalloc.
+///
+/// See [`register_error_handler`](crate::hooks::register_error_handler).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// A double free was detected (only in debug and secure mode).
+    DoubleFree,
+    /// Not enough memory available to satisfy the request.
+    OutOfMemory,
+    /// A corrupted free list or meta-data was detected (only in debug and
+    /// secure mode).
+    CorruptedMetadata,
+    /// Trying to free or re-allocate an invalid pointer.
+    InvalidPointer,
+    /// Too large a request, for example `count * size` overflowing.
+    Overflow,
+    /// An alignment which isn't a power of two. Never reported by mimalloc,
+    /// only by the checked wrappers before calling into it.
+    InvalidAlignment,
+    /// A size which isn't a multiple of the alignment, as C11's
+    /// `aligned_alloc` requires. Never reported by mimalloc, only by
+    /// [`try_aligned_alloc`](crate::allocator::Allocator::try_aligned_alloc).
+    InvalidSize,
+    /// An error code mimalloc isn't documented to report.
+    Other(i32),
+}
+
+impl AllocError {
+    /// Translate a mimalloc error code (an `errno` value).
+    pub fn from_code(code: i32) -> Self {
+        match code {
+            | 11 => AllocError::DoubleFree,
+            | 12 => AllocError::OutOfMemory,
+            | 14 => AllocError::CorruptedMetadata,
+            | 22 => AllocError::InvalidPointer,
+            | 75 => AllocError::Overflow,
+            | code => AllocError::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            | AllocError::DoubleFree => write!(f, "double free detected"),
+            | AllocError::OutOfMemory => write!(f, "out of memory"),
+            | AllocError::CorruptedMetadata => write!(f, "corrupted free list or meta-data"),
+            | AllocError::InvalidPointer => write!(f, "invalid pointer"),
+            | AllocError::Overflow => write!(f, "allocation request is too large"),
+            | AllocError::InvalidAlignment => write!(f, "alignment is not a power of two"),
+            | AllocError::InvalidSize => write!(f, "size is not a multiple of the alignment"),
+            | AllocError::Other(code) => write!(f, "allocator error {}", code),
+        }
+    }
+}
+
+impl Error for AllocError {}
+
+/// Returned when a human-readable byte size such as `"512m"` can't be parsed.
+///
+/// See [`parse_size`](crate::reserve::parse_size).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseSizeError {
+    /// The size is empty.
+    Empty,
+    /// The number before the suffix isn't a valid unsigned integer.
+    InvalidNumber,
+    /// The suffix isn't one of `k`, `m` or `g`.
+    InvalidSuffix,
+    /// The size doesn't fit in a `usize`.
+    Overflow,
+}
+
+impl fmt::Display for ParseSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            | ParseSizeError::Empty => write!(f, "empty size"),
+            | ParseSizeError::InvalidNumber => write!(f, "invalid number in size"),
+            | ParseSizeError::InvalidSuffix => write!(f, "invalid size suffix, expected k, m or g"),
+            | ParseSizeError::Overflow => write!(f, "size is too large"),
+        }
+    }
+}
+
+impl Error for ParseSizeError {}
+
+/// Returned when reserving OS memory from a size string fails.
+///
+/// See [`reserve_os_memory`](crate::reserve::reserve_os_memory).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReserveError {
+    /// The size couldn't be parsed.
+    InvalidSize(ParseSizeError),
+    /// mimalloc failed to reserve the memory.
+    Alloc(AllocError),
+}
+
+impl fmt::Display for ReserveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            | ReserveError::InvalidSize(err) => write!(f, "{}", err),
+            | ReserveError::Alloc(err) => write!(f, "failed to reserve memory: {}", err),
+        }
+    }
+}
+
+impl Error for ReserveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            | ReserveError::InvalidSize(err) => Some(err),
+            | ReserveError::Alloc(err) => Some(err),
+        }
+    }
+}
+
+impl From<ParseSizeError> for ReserveError {
+    fn from(err: ParseSizeError) -> Self {
+        ReserveError::InvalidSize(err)
+    }
+}
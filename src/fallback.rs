@@ -0,0 +1,136 @@
+use std::{
+    ffi::c_void,
+    sync::Arc,
+};
+
+use cesium_libmimalloc_sys::allocator::mi_usable_size;
+
+use crate::{
+    allocator::Allocator,
+    budget::Budget,
+};
+
+/// Which allocator a block handed out by a [`FallbackAllocator`] came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// The block came from the primary allocator.
+    Primary,
+    /// The block came from the secondary (fallback) allocator.
+    Secondary,
+}
+
+/// Allocates from a primary allocator, falling back to a secondary one when the
+/// primary is exhausted.
+///
+/// The primary is exhausted when it returns null, or when an allocation would
+/// take it past its optional byte budget. Blocks are routed back to the right
+/// allocator on [`dealloc`](FallbackAllocator::dealloc) by checking which heap
+/// contains them, so no per-block bookkeeping is needed.
+pub struct FallbackAllocator {
+    primary: Arc<Allocator>,
+    secondary: Arc<Allocator>,
+    primary_budget: Budget,
+}
+
+impl FallbackAllocator {
+    /// Create an allocator preferring `primary` and falling back to
+    /// `secondary`, such as the default heap.
+    pub fn new(primary: Arc<Allocator>, secondary: Arc<Allocator>) -> Self {
+        FallbackAllocator {
+            primary,
+            secondary,
+            primary_budget: Budget::new(usize::MAX),
+        }
+    }
+
+    /// Limit the live bytes allocated from the primary, as measured by the
+    /// usable size of each block.
+    pub fn with_primary_budget(mut self, max_bytes: usize) -> Self {
+        self.primary_budget = Budget::new(max_bytes);
+        self
+    }
+
+    /// Allocate `size` bytes from the primary, or from the secondary if the
+    /// primary is exhausted.
+    ///
+    /// Returns null if both are out of memory.
+    pub fn alloc(&self, size: usize) -> *mut u8 {
+        if self.primary_budget.fits(size) {
+            let p = self.primary.malloc(size);
+            if !p.is_null() {
+                if self.primary_budget.charge(usable_size(p)) {
+                    return p;
+                }
+                // a concurrent allocation spent the budget first
+                self.primary.free(p);
+            }
+        }
+
+        self.secondary.malloc(size)
+    }
+
+    /// Free a block allocated by [`alloc`](FallbackAllocator::alloc) through
+    /// the allocator it came from.
+    pub fn dealloc(&self, p: *mut u8) {
+        if p.is_null() {
+            return;
+        }
+
+        match self.origin(p) {
+            | Origin::Primary => {
+                self.primary_budget.release(usable_size(p));
+                self.primary.free(p);
+            },
+            | Origin::Secondary => self.secondary.free(p),
+        }
+    }
+
+    /// Which allocator the block at `p` came from.
+    ///
+    /// `p` must be a block allocated by [`alloc`](FallbackAllocator::alloc).
+    pub fn origin(&self, p: *const u8) -> Origin {
+        if self.primary.contains_block(p) {
+            Origin::Primary
+        } else {
+            Origin::Secondary
+        }
+    }
+
+    /// Live bytes currently allocated from the primary.
+    pub fn primary_live(&self) -> usize {
+        self.primary_budget.live()
+    }
+}
+
+fn usable_size(p: *const u8) -> usize {
+    unsafe { mi_usable_size(p as *const c_void) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AllocatorPool;
+
+    #[test]
+    fn falls_back_when_primary_budget_is_spent() {
+        let mut pool = AllocatorPool::new();
+        let primary = pool.new_allocator();
+        let secondary = Arc::new(Allocator::default());
+        let fallback = FallbackAllocator::new(primary.clone(), secondary).with_primary_budget(1024);
+
+        let a = fallback.alloc(512);
+        let b = fallback.alloc(512);
+        let c = fallback.alloc(512);
+        assert!(!a.is_null() && !b.is_null() && !c.is_null());
+
+        assert_eq!(fallback.origin(a), Origin::Primary);
+        assert_eq!(fallback.origin(b), Origin::Primary);
+        assert_eq!(fallback.origin(c), Origin::Secondary);
+
+        for p in [a, b, c] {
+            fallback.dealloc(p);
+        }
+        assert_eq!(fallback.primary_live(), 0);
+        assert_eq!(primary.live_block_count(), 0);
+    }
+}
@@ -0,0 +1,88 @@
+use crate::allocator::Allocator;
+
+/// Tracks a heap's fragmentation ratio over time in a fixed-size ring buffer.
+///
+/// Samples are only taken on demand, with
+/// [`sample`](FragmentationMonitor::sample)
+/// or [`record`](FragmentationMonitor::record). Once the buffer is full, each
+/// new sample evicts the oldest one.
+pub struct FragmentationMonitor {
+    samples: Vec<f64>,
+    capacity: usize,
+}
+
+impl FragmentationMonitor {
+    /// Create a monitor keeping at most `capacity` samples.
+    ///
+    /// A `capacity` of 0 is treated as 1.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        FragmentationMonitor {
+            samples: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Sample the current [`fragmentation`](Allocator::fragmentation) of
+    /// `alloc` and record it.
+    pub fn sample(&mut self, alloc: &Allocator) -> f64 {
+        let ratio = alloc.fragmentation();
+        self.record(ratio);
+        ratio
+    }
+
+    /// Record a fragmentation sample, evicting the oldest if the buffer is
+    /// full.
+    pub fn record(&mut self, ratio: f64) {
+        if self.samples.len() == self.capacity {
+            self.samples.rotate_left(1);
+            self.samples.pop();
+        }
+        self.samples.push(ratio);
+    }
+
+    /// The recorded samples, oldest first.
+    pub fn samples(&self) -> &[f64] {
+        &self.samples
+    }
+
+    /// The slope of the least-squares line through the samples, per sample.
+    ///
+    /// A positive trend means fragmentation is growing. Returns `0.0` with
+    /// fewer than two samples.
+    pub fn trend(&self) -> f64 {
+        let n = self.samples.len();
+        if n < 2 {
+            return 0.0;
+        }
+
+        let mean_x = (n - 1) as f64 / 2.0;
+        let mean_y = self.samples.iter().sum::<f64>() / n as f64;
+
+        let mut covariance = 0.0;
+        let mut variance = 0.0;
+        for (x, y) in self.samples.iter().enumerate() {
+            let dx = x as f64 - mean_x;
+            covariance += dx * (y - mean_y);
+            variance += dx * dx;
+        }
+
+        covariance / variance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn increasing_fragmentation_trends_up() {
+        let mut monitor = FragmentationMonitor::new(4);
+        for ratio in [0.1, 0.2, 0.3, 0.4, 0.5, 0.6] {
+            monitor.record(ratio);
+        }
+
+        assert_eq!(monitor.samples(), &[0.3, 0.4, 0.5, 0.6]);
+        assert!(monitor.trend() > 0.0);
+    }
+}
@@ -0,0 +1,314 @@
+use std::{
+    alloc::{
+        GlobalAlloc,
+        Layout,
+    },
+    cell::Cell,
+    ffi::c_void,
+    panic::{
+        self,
+        AssertUnwindSafe,
+    },
+    process,
+    sync::OnceLock,
+};
+
+use cesium_libmimalloc_sys::{
+    allocator::{
+        mi_free,
+        mi_malloc_aligned,
+        mi_realloc_aligned,
+        mi_thread_done,
+        mi_zalloc_aligned,
+    },
+    heap::{
+        mi_heap_get_default,
+        mi_heap_malloc_aligned,
+        mi_heap_new,
+        mi_heap_realloc_aligned,
+        mi_heap_t,
+        mi_heap_zalloc_aligned,
+    },
+};
+
+type OomHook = Box<dyn Fn(Layout) + Send + Sync>;
+
+static OOM_HOOK: OnceLock<OomHook> = OnceLock::new();
+
+thread_local! {
+    static IN_OOM_HOOK: Cell<bool> = const { Cell::new(false) };
+    static THREAD_HEAP: ThreadHeap = const { ThreadHeap(Cell::new(std::ptr::null_mut())) };
+}
+
+/// mimalloc as the global allocator.
+///
+/// ```no_run
+/// use cesium_allocator::global::GlobalAllocator;
+///
+/// #[global_allocator]
+/// static GLOBAL: GlobalAllocator = GlobalAllocator;
+/// ```
+///
+/// Allocations come from the calling thread's default heap. On out-of-memory
+/// the hook set with [`set_oom_hook`] runs before null is returned to std.
+pub struct GlobalAllocator;
+
+unsafe impl GlobalAlloc for GlobalAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = mi_malloc_aligned(layout.size(), layout.align()) as *mut u8;
+        if p.is_null() {
+            run_oom_hook(layout);
+        }
+        p
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let p = mi_zalloc_aligned(layout.size(), layout.align()) as *mut u8;
+        if p.is_null() {
+            run_oom_hook(layout);
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        mi_free(ptr as *mut c_void)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let p = mi_realloc_aligned(ptr as *mut c_void, new_size, layout.align()) as *mut u8;
+        if p.is_null() {
+            run_oom_hook(Layout::from_size_align_unchecked(new_size, layout.align()));
+        }
+        p
+    }
+}
+
+/// mimalloc as the global allocator, with a heap of its own for every thread.
+///
+/// ```no_run
+/// use cesium_allocator::global::PooledGlobal;
+///
+/// #[global_allocator]
+/// static GLOBAL: PooledGlobal = PooledGlobal;
+/// ```
+///
+/// Each thread allocates from a heap created on its first allocation, so
+/// threads never contend on a heap. Blocks may be freed from any thread with
+/// `mi_free`. When the thread exits the heap is cleaned up with
+/// `mi_thread_done`, and its live blocks move to the backing heap so they
+/// stay valid.
+///
+/// The heaps are raw mimalloc heaps rather than [`Allocator`]s in an
+/// [`AllocatorPool`], as the pool's bookkeeping would allocate from within the
+/// allocator. If the thread's heap can't be created, or during thread
+/// teardown, allocations fall back to the thread's default heap like
+/// [`GlobalAllocator`]. On out-of-memory the hook set with [`set_oom_hook`]
+/// runs before null is returned to std.
+///
+/// [`Allocator`]: crate::allocator::Allocator
+/// [`AllocatorPool`]: crate::AllocatorPool
+pub struct PooledGlobal;
+
+unsafe impl GlobalAlloc for PooledGlobal {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = mi_heap_malloc_aligned(thread_heap(), layout.size(), layout.align()) as *mut u8;
+        if p.is_null() {
+            run_oom_hook(layout);
+        }
+        p
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let p = mi_heap_zalloc_aligned(thread_heap(), layout.size(), layout.align()) as *mut u8;
+        if p.is_null() {
+            run_oom_hook(layout);
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        mi_free(ptr as *mut c_void)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let p = mi_heap_realloc_aligned(thread_heap(), ptr as *mut c_void, new_size, layout.align())
+            as *mut u8;
+        if p.is_null() {
+            run_oom_hook(Layout::from_size_align_unchecked(new_size, layout.align()));
+        }
+        p
+    }
+}
+
+/// The heap of a thread for [`PooledGlobal`], created on first use.
+///
+/// This is a bare pointer so that neither creating nor reading it allocates.
+struct ThreadHeap(Cell<*mut mi_heap_t>);
+
+impl ThreadHeap {
+    fn get(&self) -> *mut mi_heap_t {
+        if self.0.get().is_null() {
+            self.0.set(unsafe { mi_heap_new() });
+        }
+        self.0.get()
+    }
+}
+
+impl Drop for ThreadHeap {
+    fn drop(&mut self) {
+        // deletes every heap of the thread, including this one
+        if !self.0.get().is_null() {
+            unsafe { mi_thread_done() };
+        }
+    }
+}
+
+/// The heap of the calling thread, or its default heap if it couldn't be
+/// created or was already cleaned up.
+fn thread_heap() -> *mut mi_heap_t {
+    match THREAD_HEAP.try_with(ThreadHeap::get) {
+        | Ok(heap) if !heap.is_null() => heap,
+        | _ => unsafe { mi_heap_get_default() },
+    }
+}
+
+/// Register a closure to be called with the failing layout when
+/// [`GlobalAllocator`] or [`PooledGlobal`] is out of memory, e.g. to log or
+/// dump diagnostics before std's `handle_alloc_error` aborts.
+///
+/// Only the first registration is installed. Returns `false` (dropping `f`) if
+/// a hook was already set.
+///
+/// The hook runs inside the allocator, so it must not allocate itself. If it
+/// does and that allocation fails too, the nested failure doesn't run the hook
+/// again but simply returns null.
+///
+/// The hook must not panic either: unwinding out of the global allocator is
+/// undefined behavior, so a panic in the hook aborts the process.
+pub fn set_oom_hook<F>(f: F) -> bool
+where
+    F: Fn(Layout) + Send + Sync + 'static, {
+    OOM_HOOK.set(Box::new(f)).is_ok()
+}
+
+fn run_oom_hook(layout: Layout) {
+    struct Running<'a>(&'a Cell<bool>);
+
+    impl Drop for Running<'_> {
+        fn drop(&mut self) {
+            self.0.set(false);
+        }
+    }
+
+    let Some(hook) = OOM_HOOK.get() else {
+        return;
+    };
+
+    // `try_with` fails during thread teardown, skip the hook then
+    let _ = IN_OOM_HOOK.try_with(|running| {
+        if running.replace(true) {
+            return;
+        }
+        let _running = Running(running);
+
+        // the hook can't unwind out of the allocator, abort instead
+        if panic::catch_unwind(AssertUnwindSafe(|| hook(layout))).is_err() {
+            process::abort();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{
+                AtomicUsize,
+                Ordering,
+            },
+            mpsc,
+        },
+        thread,
+    };
+
+    use super::*;
+    use crate::options::OptionsBuilder;
+
+    #[test]
+    fn oom_hook_sees_failure() {
+        static FAILED_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+        if !crate::is_isolated() {
+            assert!(crate::run_isolated("global::tests::oom_hook_sees_failure"));
+            return;
+        }
+
+        assert!(set_oom_hook(|layout| {
+            FAILED_SIZE.store(layout.size(), Ordering::SeqCst);
+        }));
+        assert!(!set_oom_hook(|_| {}));
+
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let p = unsafe { GlobalAllocator.alloc(layout) };
+        assert!(!p.is_null());
+        unsafe { GlobalAllocator.dealloc(p, layout) };
+        assert_eq!(FAILED_SIZE.load(Ordering::SeqCst), 0);
+
+        OptionsBuilder::new().limit_os_alloc(true).build();
+        let p = unsafe { GlobalAllocator.alloc(Layout::from_size_align(1 << 30, 8).unwrap()) };
+        assert!(p.is_null());
+        assert_eq!(FAILED_SIZE.load(Ordering::SeqCst), 1 << 30);
+    }
+
+    #[test]
+    fn pooled_global_stress() {
+        const THREADS: usize = 8;
+        const ROUNDS: usize = 2000;
+
+        let (tx, rx) = mpsc::channel::<(usize, usize)>();
+        let handles: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let fill = t as u8 + 1;
+                    let mut live = Vec::new();
+                    for i in 0..ROUNDS {
+                        let size = 8 + (i * 37) % 4096;
+                        let layout = Layout::from_size_align(size, 8 << (i % 4)).unwrap();
+                        let p = unsafe { PooledGlobal.alloc(layout) };
+                        assert!(!p.is_null());
+                        assert_eq!(p as usize % layout.align(), 0);
+                        unsafe { p.write_bytes(fill, layout.size()) };
+                        live.push((p, layout));
+
+                        if i % 64 == 63 {
+                            for (p, layout) in live.drain(..) {
+                                let bytes = unsafe { std::slice::from_raw_parts(p, layout.size()) };
+                                assert!(bytes.iter().all(|&b| b == fill));
+                                unsafe { PooledGlobal.dealloc(p, layout) };
+                            }
+                        }
+                    }
+
+                    // hand the rest to the main thread, freed after this heap is gone
+                    for (p, layout) in live {
+                        let p = unsafe { PooledGlobal.realloc(p, layout, layout.size() * 2) };
+                        assert!(!p.is_null());
+                        tx.send((p as usize, t)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        for (p, t) in rx {
+            let p = p as *mut u8;
+            assert_eq!(unsafe { p.read() }, t as u8 + 1);
+            unsafe { PooledGlobal.dealloc(p, Layout::new::<u8>()) };
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    ffi::{
+        c_char,
+        c_int,
+        c_ulonglong,
+        c_void,
+        CStr,
+    },
+    io::Write,
+    sync::{
+        Mutex,
+        OnceLock,
+        PoisonError,
+        RwLock,
+    },
+};
+
+use cesium_libmimalloc_sys::allocator::{
+    mi_register_deferred_free,
+    mi_register_error,
+    mi_register_output,
+};
+
+use crate::error::AllocError;
+
+type ErrorHandler = Box<dyn Fn(AllocError) + Send + Sync>;
+
+type DeferredFree = Box<dyn Fn(bool, u64) + Send + Sync>;
+
+static ERROR_HANDLER: OnceLock<ErrorHandler> = OnceLock::new();
+static DEFERRED_FREE: RwLock<Option<DeferredFree>> = RwLock::new(None);
+static OUTPUT_SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+/// Register a closure to be called on an error in mimalloc.
+///
+/// The handler is called after mimalloc emits its error message, with the
+/// error code translated into an [`AllocError`]. Returning from it is always
+/// legal, in which case allocation functions generally return null or ignore
+/// the condition. Double frees and corruption are only detected when mimalloc
+/// is built with the `secure` or `debug-full` feature.
+///
+/// mimalloc allows at most one error handler, so only the first registration
+/// is installed. Returns `false` (dropping `f`) if a handler was already
+/// registered. The handler must not panic, as it's called from C.
+pub fn register_error_handler<F>(f: F) -> bool
+where
+    F: Fn(AllocError) + Send + Sync + 'static, {
+    if ERROR_HANDLER.set(Box::new(f)).is_err() {
+        return false;
+    }
+
+    unsafe { mi_register_error(Some(error_trampoline), std::ptr::null_mut()) };
+    true
+}
+
+unsafe extern "C" fn error_trampoline(code: c_int, _arg: *mut c_void) {
+    if let Some(handler) = ERROR_HANDLER.get() {
+        handler(AllocError::from_code(code));
+    }
+}
+
+/// Register a closure to free deferred memory on demand.
+///
+/// This is for runtimes that free lazily, e.g. with reference counting to limit
+/// the worst case free time. mimalloc calls `f(force, heartbeat)`
+/// deterministically after some number of allocations, regardless of freeing
+/// or available free memory:
+///
+/// - When `force` is true, all possible memory should be freed.
+/// - The per-thread `heartbeat` is monotonically increasing and deterministic
+///   if the program allocates deterministically.
+///
+/// At most one deferred free function is active, so this replaces any earlier
+/// one. The closure must be re-entrant and must not panic, as it's called
+/// from C.
+pub fn register_deferred_free<F>(f: F)
+where
+    F: Fn(bool, u64) + Send + Sync + 'static, {
+    *DEFERRED_FREE
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = Some(Box::new(f));
+    unsafe { mi_register_deferred_free(Some(deferred_free_trampoline), std::ptr::null_mut()) };
+}
+
+/// Remove the closure registered with [`register_deferred_free`], if any.
+pub fn unregister_deferred_free() {
+    unsafe { mi_register_deferred_free(None, std::ptr::null_mut()) };
+    *DEFERRED_FREE
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+unsafe extern "C" fn deferred_free_trampoline(
+    force: bool,
+    heartbeat: c_ulonglong,
+    _arg: *mut c_void,
+) {
+    if let Some(f) = DEFERRED_FREE
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+    {
+        f(force, heartbeat);
+    }
+}
+
+/// Send mimalloc's output, such as verbose and warning messages, to `w`
+/// instead of `stderr`.
+///
+/// This replaces any earlier sink. Messages are dropped if the sink is busy,
+/// e.g. when writing to it causes mimalloc to emit another message, and write
+/// errors are ignored.
+pub fn set_output_sink<W>(w: W)
+where
+    W: Write + Send + 'static, {
+    *OUTPUT_SINK.lock().unwrap_or_else(PoisonError::into_inner) = Some(Box::new(w));
+    unsafe { mi_register_output(Some(output_trampoline), std::ptr::null_mut()) };
+}
+
+unsafe extern "C" fn output_trampoline(msg: *const c_char, _arg: *mut c_void) {
+    if msg.is_null() {
+        return;
+    }
+
+    // the sink may allocate, which may emit output again: drop that instead of
+    // deadlocking
+    if let Ok(mut sink) = OUTPUT_SINK.try_lock() {
+        if let Some(w) = sink.as_mut() {
+            let _ = w.write_all(CStr::from_ptr(msg).to_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Once;
+
+    use super::*;
+    use crate::allocator::Allocator;
+
+    /// Errors seen by the handler shared by every test in the process.
+    fn recorded_errors() -> &'static Mutex<Vec<AllocError>> {
+        static ERRORS: Mutex<Vec<AllocError>> = Mutex::new(Vec::new());
+        static REGISTER: Once = Once::new();
+
+        REGISTER.call_once(|| {
+            assert!(register_error_handler(|err| ERRORS
+                .lock()
+                .unwrap()
+                .push(err)));
+        });
+        &ERRORS
+    }
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(AllocError::from_code(11), AllocError::DoubleFree);
+        assert_eq!(AllocError::from_code(12), AllocError::OutOfMemory);
+        assert_eq!(AllocError::from_code(14), AllocError::CorruptedMetadata);
+        assert_eq!(AllocError::from_code(22), AllocError::InvalidPointer);
+        assert_eq!(AllocError::from_code(75), AllocError::Overflow);
+        assert_eq!(AllocError::from_code(1), AllocError::Other(1));
+    }
+
+    #[test]
+    fn deferred_free_fires() {
+        use std::sync::{
+            atomic::{
+                AtomicUsize,
+                Ordering,
+            },
+            Arc,
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        register_deferred_free(move |_, _| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+
+        let alloc = Allocator::default();
+        let blocks: Vec<_> = (0..100_000).map(|_| alloc.malloc(64)).collect();
+        for p in blocks {
+            alloc.free(p);
+        }
+
+        unregister_deferred_free();
+        assert!(calls.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn output_sink_captures_messages() {
+        use cesium_libmimalloc_sys::allocator::mi_stats_print_out;
+
+        use crate::options::{
+            self,
+            OptionName,
+            OptionsBuilder,
+        };
+
+        let _options = crate::lock_options();
+        let verbose = options::is_enabled(OptionName::Verbose);
+        OptionsBuilder::new().verbose(true).build();
+
+        let captured = crate::SharedBuf::default();
+        set_output_sink(captured.clone());
+        unsafe { mi_stats_print_out(None, std::ptr::null_mut()) };
+
+        unsafe { mi_register_output(None, std::ptr::null_mut()) };
+        OptionsBuilder::new().verbose(verbose).build();
+        assert!(!captured.is_empty());
+    }
+
+    #[test]
+    fn handler_sees_overflow() {
+        let errors = recorded_errors();
+        assert!(!register_error_handler(|_| {}));
+
+        let alloc = Allocator::default();
+        assert!(alloc.calloc(usize::MAX, 2).is_null());
+        assert!(errors.lock().unwrap().contains(&AllocError::Overflow));
+    }
+
+    #[test]
+    #[cfg(any(feature = "secure", feature = "debug-full"))]
+    fn handler_sees_double_free() {
+        let errors = recorded_errors();
+
+        let alloc = Allocator::default();
+        let keep = alloc.malloc(32);
+        let p = alloc.malloc(32);
+        alloc.free(p);
+        alloc.free(p);
+
+        assert!(errors.lock().unwrap().contains(&AllocError::DoubleFree));
+        alloc.free(keep);
+    }
+}
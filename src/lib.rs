@@ -1,18 +1,104 @@
 pub mod allocator;
+pub mod area;
+pub mod boxed;
+pub mod budget;
+pub mod commit;
+pub mod cstr;
+pub mod error;
+pub mod fallback;
+pub mod fragmentation;
+pub mod global;
+pub mod hooks;
+pub mod options;
+pub mod reserve;
+pub mod sharded;
+pub mod slice;
+pub mod stats;
+pub mod thread;
+#[cfg(feature = "tracking")]
+pub mod tracking;
 
 use std::{
-    collections::BTreeMap,
-    sync::Arc,
+    cmp::Reverse,
+    collections::{
+        BTreeMap,
+        BinaryHeap,
+    },
+    fmt,
+    sync::{
+        Arc,
+        Once,
+        OnceLock,
+        PoisonError,
+        RwLock,
+        Weak,
+    },
 };
 
-use cesium_libmimalloc_sys::heap::mi_heap_new;
+use cesium_libmimalloc_sys::{
+    allocator::{
+        mi_collect,
+        mi_process_init,
+        mi_stats_reset,
+        mi_version,
+    },
+    heap::{
+        mi_heap_new,
+        mi_heap_new_in_arena,
+        mi_heap_t,
+    },
+    mi_arena_id_t,
+};
 
-use crate::allocator::Allocator;
+use crate::{
+    allocator::{
+        AllocPolicy,
+        Allocator,
+    },
+    area::HeapUsage,
+    error::IdInUse,
+    options::{
+        restore_options,
+        snapshot_options,
+        OptionName,
+        OptionSnapshot,
+    },
+    stats::AllocSnapshot,
+};
 
 /// A pool of general allocators.
+///
+/// Ids of removed allocators are recycled, smallest first, once nothing else
+/// references the removed allocator.
 pub struct AllocatorPool {
     lowest_id: u32,
-    heaps: BTreeMap<u32, Arc<Allocator>>,
+    policy: AllocPolicy,
+    weak_entries: bool,
+    heaps: BTreeMap<u32, Entry>,
+    free_ids: BinaryHeap<Reverse<u32>>,
+    released: Vec<(u32, Weak<Allocator>)>,
+}
+
+/// How the pool holds an allocator, see [`AllocatorPool::set_weak_entries`].
+enum Entry {
+    Strong(Arc<Allocator>),
+    Weak(Weak<Allocator>),
+}
+
+impl Entry {
+    fn upgrade(&self) -> Option<Arc<Allocator>> {
+        match self {
+            | Entry::Strong(alloc) => Some(alloc.clone()),
+            | Entry::Weak(alloc) => alloc.upgrade(),
+        }
+    }
+
+    fn downgrade(&self) -> Weak<Allocator> {
+        match self {
+            | Entry::Strong(alloc) => Arc::downgrade(alloc),
+            | Entry::Weak(alloc) => alloc.clone(),
+        }
+    }
 }
 
 impl AllocatorPool {
@@ -20,30 +106,1167 @@ impl AllocatorPool {
     pub fn new() -> Self {
         AllocatorPool {
             lowest_id: 0,
+            policy: AllocPolicy::ReturnNull,
+            weak_entries: false,
             heaps: BTreeMap::new(),
+            free_ids: BinaryHeap::new(),
+            released: Vec::new(),
         }
     }
 
+    /// Create a pool with `n` allocators already created, with ids `1..=n`.
+    ///
+    /// This avoids creating allocators lazily on the first requests. The heaps
+    /// belong to the calling thread, like those of
+    /// [`new_allocator`](AllocatorPool::new_allocator).
+    pub fn with_capacity(n: u32) -> Self {
+        let mut pool = AllocatorPool::new();
+        for _ in 0..n {
+            pool.new_allocator();
+        }
+        pool
+    }
+
+    /// Set what happens when an allocation fails in allocators created from
+    /// now on, see [`AllocPolicy`].
+    ///
+    /// Existing allocators keep their policy.
+    pub fn set_alloc_policy(&mut self, policy: AllocPolicy) {
+        self.policy = policy;
+    }
+
+    /// Only hold weak references to allocators created from now on.
+    ///
+    /// By default the pool keeps every allocator alive until it's removed.
+    /// With weak entries an allocator is dropped, deleting its heap, once
+    /// every `Arc` handed out for it is dropped, so a consumer forgetting to
+    /// remove it can't keep it alive forever. The dead entries are cleaned up
+    /// by [`reap_unused`](AllocatorPool::reap_unused).
+    pub fn set_weak_entries(&mut self, weak: bool) {
+        self.weak_entries = weak;
+    }
+
     /// Create a new allocator
+    ///
+    /// Reuses the smallest recycled id if there is one.
     pub fn new_allocator(&mut self) -> Arc<Allocator> {
         let heap = unsafe { mi_heap_new() };
-        let id = self.lowest_id + 1;
-        self.lowest_id = id;
+        self.insert_heap(heap, self.policy, None)
+    }
 
-        let alloc = Arc::new(Allocator::new(id, heap));
-        self.heaps.insert(id, alloc.clone());
+    /// Create a new allocator tagged with `name`, see
+    /// [`Allocator::with_name`].
+    ///
+    /// The name stays in the process: mimalloc only has a single OS tag for
+    /// all of its memory (`os_tag`, used on macOS), not one per heap. To tell
+    /// the memory apart at the OS level, set
+    /// [`OptionsBuilder::os_tag`](options::OptionsBuilder::os_tag) to a
+    /// numeric id before allocating.
+    pub fn new_named_allocator(&mut self, name: impl Into<String>) -> Arc<Allocator> {
+        let heap = unsafe { mi_heap_new() };
+        self.insert_heap(heap, self.policy, Some(name.into()))
+    }
 
-        alloc
+    /// Create a new allocator which only allocates out of the arena
+    /// `arena_id`.
+    ///
+    /// See [`reserve_arena`](crate::reserve::reserve_arena). Returns `None` if
+    /// mimalloc couldn't create the heap.
+    pub fn new_allocator_in_arena(&mut self, arena_id: mi_arena_id_t) -> Option<Arc<Allocator>> {
+        let heap = unsafe { mi_heap_new_in_arena(arena_id) };
+        if heap.is_null() {
+            return None;
+        }
+
+        Some(self.insert_heap(heap, self.policy, None))
+    }
+
+    /// Create a new allocator standing in for a copy of allocator `id`, with
+    /// the same [`AllocPolicy`] and name.
+    ///
+    /// Allocators own their heap and can't be cloned, so this creates a
+    /// genuinely separate heap bound to the next free id; blocks allocated
+    /// from one are never owned by the other. The new heap belongs to the
+    /// calling thread. Returns `None` if allocator `id` isn't alive in the
+    /// pool.
+    pub fn duplicate_heap(&mut self, id: u32) -> Option<Arc<Allocator>> {
+        let source = self.heaps.get(&id).and_then(Entry::upgrade)?;
+
+        let heap = unsafe { mi_heap_new() };
+        Some(self.insert_heap(heap, source.policy(), source.name().map(String::from)))
+    }
+
+    /// Create a new allocator preferring NUMA node `node`.
+    ///
+    /// NUMA placement in mimalloc is process-wide and option-driven, and the
+    /// options (such as `reserve_huge_os_pages_at`) are only read at process
+    /// start, so this can't steer a single heap: mimalloc allocates segments on
+    /// the node of the calling thread. Call it from a thread running on
+    /// `node`, or for strict affinity reserve memory on the node and create
+    /// the allocator in that arena with
+    /// [`new_allocator_in_arena`](AllocatorPool::new_allocator_in_arena).
+    ///
+    /// Returns `None` if `node` is past the number of nodes configured with
+    /// `use_numa_nodes`. When that option is 0 (the detected count) any node
+    /// is accepted.
+    pub fn new_allocator_on_numa(&mut self, node: u32) -> Option<Arc<Allocator>> {
+        let nodes = options::get(OptionName::UseNumaNodes);
+        if nodes > 0 && i64::from(node) >= nodes {
+            return None;
+        }
+
+        Some(self.new_allocator())
     }
 
     /// Gets or creates an allocator
     pub fn get_allocator(&mut self, id: u32, create: Option<bool>) -> Option<Arc<Allocator>> {
-        match self.heaps.get(&id) {
+        match self.heaps.get(&id).and_then(Entry::upgrade) {
             | None => match create {
                 | None => None,
                 | Some(_) => Some(self.new_allocator()),
             },
-            | Some(v) => Some(v.clone()),
+            | Some(v) => Some(v),
+        }
+    }
+
+    /// Get allocator `id`, creating it bound to exactly that id if it isn't in
+    /// the pool.
+    ///
+    /// Unlike [`get_allocator`](AllocatorPool::get_allocator), the id is
+    /// chosen by the caller, e.g. a shard number. Later auto-assigned ids
+    /// won't collide with it.
+    pub fn get_or_create(&mut self, id: u32) -> Arc<Allocator> {
+        if let Some(alloc) = self.heaps.get(&id).and_then(Entry::upgrade) {
+            return alloc;
+        }
+
+        // a removed allocator still referenced elsewhere keeps its id, so it
+        // goes back into the pool rather than sharing the id with a new one
+        if let Some(alloc) = self.released_alive(id) {
+            self.released.retain(|(released, _)| *released != id);
+            self.heaps.insert(id, self.entry(&alloc));
+            return alloc;
+        }
+
+        let heap = unsafe { mi_heap_new() };
+        let alloc = Arc::new(Allocator::new(id, heap).with_policy(self.policy));
+        self.heaps.insert(id, self.entry(&alloc));
+        self.lowest_id = self.lowest_id.max(id);
+
+        alloc
+    }
+
+    /// Get a weak reference to allocator `id`, which doesn't keep it alive.
+    pub fn get_weak(&self, id: u32) -> Option<Weak<Allocator>> {
+        self.heaps.get(&id).map(Entry::downgrade)
+    }
+
+    /// Remove the weak entries whose allocator has been dropped, returning
+    /// how many were removed. Their ids are free to be reused right away.
+    ///
+    /// See [`set_weak_entries`](AllocatorPool::set_weak_entries).
+    pub fn reap_unused(&mut self) -> usize {
+        let before = self.heaps.len();
+        let free_ids = &mut self.free_ids;
+        self.heaps.retain(|id, entry| match entry {
+            | Entry::Weak(alloc) if alloc.strong_count() == 0 => {
+                free_ids.push(Reverse(*id));
+                false
+            },
+            | _ => true,
+        });
+
+        before - self.heaps.len()
+    }
+
+    /// Remove an allocator from the pool, returning whether it existed.
+    ///
+    /// The pool only drops its own reference: if other `Arc` clones are still
+    /// alive this just decrements the refcount, and the heap is deleted once
+    /// the last clone is dropped. The id is only recycled once that happens, so
+    /// two live allocators never share an id.
+    pub fn remove_allocator(&mut self, id: u32) -> bool {
+        match self.heaps.remove(&id) {
+            | None => false,
+            | Some(entry) => {
+                self.released.push((id, entry.downgrade()));
+                true
+            },
+        }
+    }
+
+    /// Run `f` with a fresh allocator, deleting its heap once `f` returns.
+    ///
+    /// For a phase of work whose allocations should all be released together,
+    /// e.g. a request. The allocator gets an id from this pool, but isn't kept
+    /// in it. Blocks still allocated when the heap is deleted migrate to the
+    /// default heap, so they stay valid but must still be freed. The return
+    /// value can safely outlive the scope as long as it doesn't borrow the
+    /// allocator: plain values, or pointers to blocks the caller then frees.
+    pub fn scoped<R, F>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&Allocator) -> R, {
+        let alloc = self.new_allocator();
+        // the id is recycled once `alloc` is dropped, even if `f` panics
+        self.remove_allocator(alloc.id());
+
+        f(&alloc)
+    }
+
+    /// Remove every allocator from the pool and start numbering from 1 again.
+    ///
+    /// Heaps only referenced by the pool are deleted right away. Allocators
+    /// still referenced elsewhere survive, but unlike with
+    /// [`remove_allocator`](AllocatorPool::remove_allocator) their ids are
+    /// forgotten and may be handed out again.
+    pub fn clear(&mut self) {
+        self.heaps.clear();
+        self.free_ids.clear();
+        self.released.clear();
+        self.lowest_id = 0;
+    }
+
+    /// Iterate over every allocator in the pool, in ascending id order.
+    ///
+    /// Weak entries whose allocator has been dropped are skipped.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, Arc<Allocator>)> + '_ {
+        self.heaps
+            .iter()
+            .filter_map(|(id, entry)| Some((*id, entry.upgrade()?)))
+    }
+
+    /// Iterate over the allocators whose heap was created by the calling
+    /// thread, as only those can be walked or collected here.
+    fn iter_local(&self) -> impl Iterator<Item = (u32, Arc<Allocator>)> + '_ {
+        let current = std::thread::current().id();
+        self.iter()
+            .filter(move |(_, alloc)| alloc.owner() == current)
+    }
+
+    /// The number of allocators in the pool.
+    ///
+    /// This includes weak entries which haven't been reaped yet.
+    pub fn len(&self) -> usize {
+        self.heaps.len()
+    }
+
+    /// Whether the pool has no allocators.
+    pub fn is_empty(&self) -> bool {
+        self.heaps.is_empty()
+    }
+
+    /// Find the id of the allocator owning the block at `p`, among the heaps
+    /// created by the calling thread.
+    ///
+    /// `p` may be any pointer, see [`Allocator::check_owned`]. That's linear
+    /// in the pages of a heap and is done for every heap until the owner is
+    /// found, so this is expensive: O(heaps × pages). The heaps of other
+    /// threads can't be walked here, so their blocks aren't found.
+    pub fn owner_of(&self, p: *const u8) -> Option<u32> {
+        self.iter_local()
+            .find(|(_, alloc)| alloc.check_owned(p))
+            .map(|(id, _)| id)
+    }
+
+    /// Remove allocator `id` from the pool and destroy its heap, freeing every
+    /// block in it at once. See [`Allocator::destroy`].
+    ///
+    /// Returns `false`, leaving the allocator in the pool, if `id` isn't in
+    /// the pool, is only weakly held by it, or other `Arc` clones of it are
+    /// still alive. Otherwise the id is free to be reused right away.
+    ///
+    /// # Safety
+    ///
+    /// Every pointer allocated from the heap becomes dangling, see
+    /// [`Allocator::destroy`].
+    pub unsafe fn destroy_allocator(&mut self, id: u32) -> bool {
+        let alloc = match self.heaps.remove(&id) {
+            | Some(Entry::Strong(alloc)) => alloc,
+            | Some(entry) => {
+                self.heaps.insert(id, entry);
+                return false;
+            },
+            | None => return false,
+        };
+
+        match Arc::try_unwrap(alloc) {
+            | Ok(alloc) => {
+                alloc.destroy();
+                self.free_ids.push(Reverse(id));
+                true
+            },
+            | Err(alloc) => {
+                self.heaps.insert(id, Entry::Strong(alloc));
+                false
+            },
+        }
+    }
+
+    /// Release outstanding resources in every heap in the pool created by the
+    /// calling thread.
+    ///
+    /// See [`Allocator::collect`]. Like allocating, collecting a heap can only
+    /// happen on the thread that created it, so the heaps of other threads
+    /// are skipped.
+    pub fn collect_all(&self, force: bool) {
+        for (_, alloc) in self.iter_local() {
+            alloc.collect(force);
+        }
+    }
+
+    /// Sum the memory held by every heap in the pool created by the calling
+    /// thread.
+    ///
+    /// See [`Allocator::live_bytes`]. This walks the areas of every heap, so
+    /// it's linear in the total number of areas in the pool. Like collecting,
+    /// walking a heap can only happen on the thread that created it, so the
+    /// heaps of other threads are skipped.
+    pub fn total_usage(&self) -> HeapUsage {
+        let mut usage = HeapUsage::default();
+        for (_, alloc) in self.iter_local() {
+            usage.add_usage(&alloc.live_bytes());
+        }
+        usage
+    }
+
+    /// Capture process information along with the usage of the pool.
+    ///
+    /// Like [`total_usage`](AllocatorPool::total_usage), this is linear in the
+    /// total number of areas in the pool, and only the heaps created by the
+    /// calling thread are counted in the usage.
+    pub fn snapshot(&self) -> AllocSnapshot {
+        let info = stats::process_info();
+        let usage = self.total_usage();
+
+        AllocSnapshot {
+            elapsed_msecs: info.elapsed_msecs,
+            current_rss: info.current_rss,
+            peak_rss: info.peak_rss,
+            current_commit: info.current_commit,
+            peak_commit: info.peak_commit,
+            page_faults: info.page_faults,
+            pool_used: usage.used,
+            pool_committed: usage.committed,
+            heap_count: self.len(),
+        }
+    }
+
+    /// Create an allocator for each of `ids`, returning them in the same order.
+    ///
+    /// This is all-or-nothing: if any id is already in the pool (or repeated in
+    /// `ids`), no allocators are created and the colliding id is returned.
+    pub fn reserve_ids(&mut self, ids: &[u32]) -> Result<Vec<Arc<Allocator>>, IdInUse> {
+        for (i, id) in ids.iter().enumerate() {
+            if self.heaps.contains_key(id) ||
+                self.released_alive(*id).is_some() ||
+                ids[..i].contains(id)
+            {
+                return Err(IdInUse(*id));
+            }
+        }
+
+        let allocs = ids
+            .iter()
+            .map(|id| {
+                let heap = unsafe { mi_heap_new() };
+                let alloc = Arc::new(Allocator::new(*id, heap).with_policy(self.policy));
+                self.heaps.insert(*id, self.entry(&alloc));
+                alloc
+            })
+            .collect();
+
+        if let Some(max) = ids.iter().max() {
+            self.lowest_id = self.lowest_id.max(*max);
+        }
+
+        Ok(allocs)
+    }
+
+    /// Add an allocator from another pool under a fresh id, returning that id.
+    ///
+    /// The allocator keeps reporting its original [`id`](Allocator::id), so
+    /// look it up in this pool by the returned id. If the allocator (or another
+    /// one over the same heap) is already in this pool, its existing id is
+    /// returned instead.
+    pub fn import(&mut self, alloc: Arc<Allocator>) -> u32 {
+        let existing = self
+            .iter()
+            .find(|(_, other)| Arc::ptr_eq(other, &alloc) || other.heap() == alloc.heap());
+        if let Some((id, _)) = existing {
+            return id;
+        }
+
+        let id = self.fresh_id();
+        self.heaps.insert(id, self.entry(&alloc));
+        id
+    }
+
+    /// Bind `heap` to the next free id, optionally named.
+    fn insert_heap(
+        &mut self,
+        heap: *mut mi_heap_t,
+        policy: AllocPolicy,
+        name: Option<String>,
+    ) -> Arc<Allocator> {
+        let id = self.fresh_id();
+
+        let mut alloc = Allocator::new(id, heap).with_policy(policy);
+        if let Some(name) = name {
+            alloc = alloc.with_name(name);
+        }
+        let alloc = Arc::new(alloc);
+        self.heaps.insert(id, self.entry(&alloc));
+
+        alloc
+    }
+
+    /// The smallest recycled id, or a new one past the highest id handed out.
+    fn fresh_id(&mut self) -> u32 {
+        match self.next_free_id() {
+            | Some(id) => id,
+            | None => {
+                self.lowest_id += 1;
+                self.lowest_id
+            },
+        }
+    }
+
+    /// The entry holding `alloc`, depending on whether entries are weak.
+    fn entry(&self, alloc: &Arc<Allocator>) -> Entry {
+        if self.weak_entries {
+            Entry::Weak(Arc::downgrade(alloc))
+        } else {
+            Entry::Strong(alloc.clone())
+        }
+    }
+
+    /// The allocator removed from the pool with `id`, if it's still alive.
+    fn released_alive(&self, id: u32) -> Option<Arc<Allocator>> {
+        self.released
+            .iter()
+            .filter(|(released, _)| *released == id)
+            .find_map(|(_, alloc)| alloc.upgrade())
+    }
+
+    /// Pop the smallest recycled id that isn't bound to an allocator.
+    fn next_free_id(&mut self) -> Option<u32> {
+        // ids only become free once nothing references the removed allocator
+        let free_ids = &mut self.free_ids;
+        self.released.retain(|(id, alloc)| {
+            if alloc.strong_count() > 0 {
+                return true;
+            }
+            free_ids.push(Reverse(*id));
+            false
+        });
+
+        while let Some(Reverse(id)) = self.free_ids.pop() {
+            // the id may have been bound again explicitly since it was freed
+            if !self.heaps.contains_key(&id) {
+                return Some(id);
+            }
+        }
+
+        None
+    }
+}
+
+impl fmt::Debug for AllocatorPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AllocatorPool")
+            .field("lowest_id", &self.lowest_id)
+            .field("ids", &self.heaps.keys().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for AllocatorPool {
+    fn default() -> Self {
+        AllocatorPool::new()
+    }
+}
+
+/// A thread-safe pool of general allocators.
+///
+/// This is an [`AllocatorPool`] behind a lock, so it can be shared between
+/// threads (e.g. in an `Arc`) and used through `&self`. Remember that a heap
+/// should only be allocated from on the thread that created it.
+pub struct SyncAllocatorPool {
+    inner: RwLock<AllocatorPool>,
+}
+
+impl SyncAllocatorPool {
+    /// Create a new thread-safe pool for allocators.
+    pub fn new() -> Self {
+        SyncAllocatorPool {
+            inner: RwLock::new(AllocatorPool::new()),
+        }
+    }
+
+    /// Create a new allocator
+    pub fn new_allocator(&self) -> Arc<Allocator> {
+        self.inner
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .new_allocator()
+    }
+
+    /// Gets or creates an allocator
+    ///
+    /// Lookups only take a read lock; the write lock is only taken when an
+    /// allocator has to be created.
+    pub fn get_allocator(&self, id: u32, create: Option<bool>) -> Option<Arc<Allocator>> {
+        let existing = self
+            .inner
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .heaps
+            .get(&id)
+            .and_then(Entry::upgrade);
+
+        match (existing, create) {
+            | (Some(v), _) => Some(v),
+            | (None, None) => None,
+            | (None, Some(_)) => self
+                .inner
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get_allocator(id, create),
+        }
+    }
+}
+
+impl Default for SyncAllocatorPool {
+    fn default() -> Self {
+        SyncAllocatorPool::new()
+    }
+}
+
+/// Eagerly free memory across the whole process.
+///
+/// If `force` is true, aggressively return memory to the OS (can be
+/// expensive!). Regular code should not have to call this, but it can be
+/// useful at an idle point in a long running service.
+pub fn collect_global(force: bool) {
+    unsafe { mi_collect(force) }
+}
+
+/// Initialize mimalloc for the process, at most once.
+///
+/// This normally happens automatically when the process loads or the first
+/// thread allocates, so it's only needed when those hooks don't run: e.g. when
+/// mimalloc is loaded by a custom loader or preloaded into a host process, or
+/// with static linking setups where the constructor is dropped. Repeated calls
+/// are no-ops.
+pub fn init_process() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| unsafe { mi_process_init() });
+}
+
+/// The version of the linked mimalloc.
+///
+/// See [`version`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    /// Major version, e.g. `2` in `2.1.2`.
+    pub major: u32,
+    /// Minor version, e.g. `1` in `2.1.2`.
+    pub minor: u32,
+    /// Patch version, e.g. `2` in `2.1.2`.
+    pub patch: u32,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Return the version of the linked mimalloc, e.g. `2.1.2`.
+///
+/// mimalloc packs the version into a single integer, such as `212`; this
+/// unpacks it.
+pub fn version() -> Version {
+    let v = unsafe { mi_version() } as u32;
+    Version {
+        major: v / 100,
+        minor: (v / 10) % 10,
+        patch: v % 10,
+    }
+}
+
+/// Option values captured by the first call to [`reset_for_test`].
+static OPTION_DEFAULTS: OnceLock<OptionSnapshot> = OnceLock::new();
+
+/// Reset the process-wide allocator state to a clean baseline.
+///
+/// This forces a collection with `mi_collect(true)`, resets the statistics,
+/// and restores every option to its default. The defaults are captured the
+/// first time this is called, so call it before changing any options, e.g. at
+/// the start of every test.
+///
+/// Note: options are not thread safe, so this shouldn't race with other
+/// threads reading or writing them.
+pub fn reset_for_test() {
+    restore_options(OPTION_DEFAULTS.get_or_init(snapshot_options));
+
+    unsafe {
+        mi_collect(true);
+        mi_stats_reset();
+    }
+}
+
+/// Run the test `test` alone in a child process, returning whether it passed.
+///
+/// For tests changing process-wide state, such as limiting OS allocation, or
+/// expected to abort. The child sees [`is_isolated`] return true.
+#[cfg(test)]
+pub(crate) fn run_isolated(test: &str) -> bool {
+    std::process::Command::new(std::env::current_exe().unwrap())
+        .args([test, "--exact", "--test-threads=1"])
+        .env("CESIUM_ISOLATED_TEST", "1")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .unwrap()
+        .success()
+}
+
+/// Serialize tests changing mimalloc options, which are process-wide and not
+/// thread safe.
+#[cfg(test)]
+pub(crate) fn lock_options() -> std::sync::MutexGuard<'static, ()> {
+    static OPTIONS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    OPTIONS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Whether this is the child process started by [`run_isolated`].
+#[cfg(test)]
+pub(crate) fn is_isolated() -> bool {
+    std::env::var_os("CESIUM_ISOLATED_TEST").is_some()
+}
+
+/// A writer appending to a buffer shared by its clones, e.g. to see what an
+/// output sink received.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuf(Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl SharedBuf {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+impl std::io::Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The peak, total, freed and current columns of the `stat` line of a
+/// [`capture_stats`](stats::capture_stats) dump, with units such as `MiB`
+/// applied.
+#[cfg(test)]
+pub(crate) fn stat_columns(text: &str, stat: &str) -> Vec<f64> {
+    let line = text
+        .lines()
+        .find(|line| line.trim_start().starts_with(&format!("{stat}:")))
+        .unwrap_or_else(|| panic!("no {stat} line in {text}"));
+
+    let mut columns: Vec<f64> = Vec::new();
+    for token in line.split_whitespace().skip(1) {
+        let scale = match token {
+            | "B" => Some(1.0),
+            | "K" | "Ki" | "KiB" => Some(1024.0),
+            | "M" | "Mi" | "MiB" => Some(1024.0 * 1024.0),
+            | "G" | "Gi" | "GiB" => Some(1024.0 * 1024.0 * 1024.0),
+            | _ => None,
+        };
+        match (scale, token.parse::<f64>()) {
+            | (Some(scale), _) => *columns.last_mut().unwrap() *= scale,
+            | (None, Ok(value)) => columns.push(value),
+            | (None, Err(_)) => break,
+        }
+    }
+    columns.truncate(4);
+    columns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserve_ids_is_all_or_nothing() {
+        let mut pool = AllocatorPool::new();
+
+        let allocs = pool.reserve_ids(&[10, 20, 30]).unwrap();
+        assert_eq!(allocs.len(), 3);
+        for id in [10, 20, 30] {
+            assert!(pool.get_allocator(id, None).is_some());
+        }
+
+        assert_eq!(pool.reserve_ids(&[40, 20, 50]).err(), Some(IdInUse(20)));
+        assert!(pool.get_allocator(40, None).is_none());
+        assert!(pool.get_allocator(50, None).is_none());
+
+        // a removed allocator which is still referenced keeps its id
+        let held = allocs[0].clone();
+        drop(allocs);
+        assert!(pool.remove_allocator(10));
+        assert_eq!(pool.reserve_ids(&[60, 10]).err(), Some(IdInUse(10)));
+        assert!(pool.get_allocator(60, None).is_none());
+
+        drop(held);
+        assert_eq!(pool.reserve_ids(&[10]).unwrap()[0].id(), 10);
+    }
+
+    #[test]
+    fn with_capacity_creates_allocators() {
+        let mut pool = AllocatorPool::with_capacity(4);
+
+        assert_eq!(pool.len(), 4);
+        assert_eq!(pool.get_allocator(3, None).unwrap().id(), 3);
+        assert_eq!(pool.new_allocator().id(), 5);
+    }
+
+    #[test]
+    fn get_or_create_binds_id() {
+        let mut pool = AllocatorPool::new();
+
+        let shard = pool.get_or_create(42);
+        assert_eq!(shard.id(), 42);
+        assert!(Arc::ptr_eq(&shard, &pool.get_or_create(42)));
+        assert_eq!(pool.len(), 1);
+
+        assert_eq!(pool.new_allocator().id(), 43);
+    }
+
+    #[test]
+    fn get_or_create_keeps_removed_alive_allocator() {
+        let mut pool = AllocatorPool::new();
+
+        let shard = pool.get_or_create(7);
+        assert!(pool.remove_allocator(7));
+
+        // still referenced, so the id is still bound to it
+        let again = pool.get_or_create(7);
+        assert!(Arc::ptr_eq(&shard, &again));
+        assert_eq!(pool.len(), 1);
+
+        drop((shard, again));
+        assert!(pool.remove_allocator(7));
+        let fresh = pool.get_or_create(7);
+        assert_eq!(fresh.id(), 7);
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn id_through_arc() {
+        let mut pool = AllocatorPool::new();
+        pool.new_allocator();
+
+        let alloc = pool.get_allocator(1, None).unwrap();
+        assert_eq!(alloc.id(), 1);
+    }
+
+    #[test]
+    fn remove_allocator() {
+        let mut pool = AllocatorPool::new();
+        let id = pool.new_allocator().id();
+
+        assert!(pool.remove_allocator(id));
+        assert!(pool.get_allocator(id, None).is_none());
+        assert!(!pool.remove_allocator(id));
+    }
+
+    #[test]
+    fn scoped_allocator() {
+        let mut pool = AllocatorPool::new();
+
+        let sum = pool.scoped(|alloc| {
+            let slice = alloc.collect_into(1..=100u64, 100).unwrap();
+            slice.iter().sum::<u64>()
+        });
+
+        assert_eq!(sum, 5050);
+        assert!(pool.is_empty());
+        assert_eq!(pool.new_allocator().id(), 1);
+    }
+
+    #[test]
+    fn import_from_another_pool() {
+        let mut a = AllocatorPool::new();
+        let mut b = AllocatorPool::new();
+        let shared = a.new_allocator();
+        b.new_allocator();
+
+        let id = b.import(shared.clone());
+        assert_eq!(id, 2);
+        assert!(Arc::ptr_eq(&b.get_allocator(id, None).unwrap(), &shared));
+        assert!(Arc::ptr_eq(&a.get_allocator(1, None).unwrap(), &shared));
+
+        assert_eq!(b.import(shared), id);
+        assert_eq!(b.len(), 2);
+        assert_eq!(b.new_allocator().id(), 3);
+    }
+
+    #[test]
+    fn clear_restarts_ids() {
+        let mut pool = AllocatorPool::with_capacity(3);
+        pool.remove_allocator(2);
+
+        pool.clear();
+        assert!(pool.is_empty());
+        assert!(pool.get_allocator(1, None).is_none());
+        assert_eq!(pool.new_allocator().id(), 1);
+        assert_eq!(pool.new_allocator().id(), 2);
+    }
+
+    #[test]
+    fn removed_ids_are_reused() {
+        let mut pool = AllocatorPool::new();
+        for _ in 0..3 {
+            pool.new_allocator();
+        }
+
+        assert!(pool.remove_allocator(2));
+        pool.new_allocator();
+        assert!(pool.get_allocator(2, None).is_some());
+        assert!(pool.get_allocator(4, None).is_none());
+    }
+
+    #[test]
+    fn referenced_ids_are_not_reused() {
+        let mut pool = AllocatorPool::new();
+        let first = pool.new_allocator();
+        pool.new_allocator();
+
+        assert!(pool.remove_allocator(1));
+        pool.new_allocator();
+        assert!(pool.get_allocator(1, None).is_none());
+        assert!(pool.get_allocator(3, None).is_some());
+
+        drop(first);
+        pool.new_allocator();
+        assert!(pool.get_allocator(1, None).is_some());
+    }
+
+    #[test]
+    fn allocator_on_numa_node() {
+        let _options = lock_options();
+        let reserve_at = options::get(OptionName::ReserveHugeOsPagesAt);
+        let mut pool = AllocatorPool::new();
+        let alloc = pool.new_allocator_on_numa(0).unwrap();
+        assert_eq!(options::get(OptionName::ReserveHugeOsPagesAt), reserve_at);
+
+        let p = alloc.malloc(1024);
+        assert!(!p.is_null());
+        alloc.free(p);
+    }
+
+    #[test]
+    fn pool_alloc_policy() {
+        let mut pool = AllocatorPool::new();
+        let before = pool.new_allocator();
+        pool.set_alloc_policy(AllocPolicy::Abort);
+        let after = pool.new_allocator();
+
+        assert_eq!(before.policy(), AllocPolicy::ReturnNull);
+        assert_eq!(after.policy(), AllocPolicy::Abort);
+    }
+
+    #[test]
+    fn pool_debug_lists_ids() {
+        let mut pool = AllocatorPool::new();
+        pool.reserve_ids(&[5, 2]).unwrap();
+
+        let text = format!("{:?}", pool);
+        assert!(text.contains("lowest_id: 5"));
+        assert!(text.contains("ids: [2, 5]"));
+    }
+
+    #[test]
+    fn iter_in_id_order() {
+        let mut pool = AllocatorPool::new();
+        assert!(pool.is_empty());
+
+        pool.reserve_ids(&[7, 3]).unwrap();
+        pool.new_allocator();
+
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.iter().count(), 3);
+        let ids: Vec<_> = pool.iter().map(|(id, alloc)| (id, alloc.id())).collect();
+        assert_eq!(ids, [(3, 3), (7, 7), (8, 8)]);
+    }
+
+    #[test]
+    fn owner_of_pointer() {
+        let mut pool = AllocatorPool::new();
+        pool.new_allocator();
+        let second = pool.new_allocator();
+        pool.new_allocator();
+
+        let p = second.malloc(64);
+        let on_stack = 0u64;
+        assert_eq!(pool.owner_of(p), Some(2));
+        assert_eq!(pool.owner_of(&on_stack as *const u64 as *const u8), None);
+
+        second.free(p);
+    }
+
+    #[test]
+    fn destroy_allocator_frees_blocks() {
+        let mut pool = AllocatorPool::new();
+        pool.new_allocator();
+        let id = {
+            let alloc = pool.new_allocator();
+            for _ in 0..1000 {
+                assert!(!alloc.malloc(256).is_null());
+            }
+            alloc.id()
+        };
+        assert!(pool.total_usage().used >= 1000 * 256);
+
+        let extra = pool.get_allocator(id, None).unwrap();
+        assert!(!unsafe { pool.destroy_allocator(id) });
+        drop(extra);
+
+        assert!(unsafe { pool.destroy_allocator(id) });
+        assert_eq!(pool.total_usage().used, 0);
+        assert!(pool.get_allocator(id, None).is_none());
+        assert_eq!(pool.new_allocator().id(), id);
+    }
+
+    #[test]
+    fn reap_weak_entries() {
+        let mut pool = AllocatorPool::new();
+        let strong = pool.new_allocator();
+        pool.set_weak_entries(true);
+        let weak = pool.new_allocator();
+        let id = weak.id();
+
+        let handle = pool.get_weak(id).unwrap();
+        assert_eq!(pool.reap_unused(), 0);
+        drop(weak);
+        assert!(handle.upgrade().is_none());
+        assert!(pool.get_allocator(id, None).is_none());
+        assert_eq!(pool.iter().count(), 1);
+
+        assert_eq!(pool.reap_unused(), 1);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.get_weak(id).is_none());
+
+        drop(strong);
+        assert_eq!(pool.reap_unused(), 0);
+        assert!(pool.get_allocator(1, None).is_some());
+    }
+
+    #[test]
+    fn collect_all_heaps() {
+        let mut pool = AllocatorPool::new();
+        let a = pool.new_allocator();
+        let b = pool.new_allocator();
+
+        let p = a.malloc(1 << 16);
+        let q = b.malloc(1 << 10);
+        a.free(p);
+
+        pool.collect_all(true);
+        collect_global(false);
+        b.free(q);
+    }
+
+    #[test]
+    fn total_usage_sums_heaps() {
+        let mut pool = AllocatorPool::new();
+        let a = pool.new_allocator();
+        let b = pool.new_allocator();
+
+        let small: Vec<_> = (0..16).map(|_| a.malloc(64)).collect();
+        let large: Vec<_> = (0..4).map(|_| b.malloc(4096)).collect();
+
+        let total = pool.total_usage();
+        let (used_a, used_b) = (a.live_bytes().used, b.live_bytes().used);
+        assert_eq!(total.used, used_a + used_b);
+        assert!(used_a >= 16 * 64 && used_b >= 4 * 4096);
+        assert_eq!(total.block_count, 20);
+
+        for p in small {
+            a.free(p);
+        }
+        for p in large {
+            b.free(p);
+        }
+    }
+
+    #[test]
+    fn other_threads_skip_heaps() {
+        let mut pool = AllocatorPool::new();
+        let a = pool.new_allocator();
+        let p = a.malloc(4096) as usize;
+
+        // the heap can't be walked off its thread, so it's left out
+        let pool = std::thread::spawn(move || {
+            assert_eq!(pool.total_usage(), HeapUsage::default());
+            assert_eq!(pool.owner_of(p as *const u8), None);
+            pool.collect_all(true);
+            pool
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(pool.owner_of(p as *const u8), Some(a.id()));
+        a.free(p as *mut u8);
+    }
+
+    #[test]
+    fn snapshot_of_pool() {
+        let mut pool = AllocatorPool::new();
+        let a = pool.new_allocator();
+        pool.new_allocator();
+        let p = a.malloc(4096);
+
+        let snapshot = pool.snapshot();
+        assert_eq!(snapshot.heap_count, 2);
+        assert!(snapshot.pool_used >= 4096);
+        assert!(snapshot.pool_committed >= snapshot.pool_used);
+        assert!(snapshot.peak_rss >= snapshot.current_rss);
+        a.free(p);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn snapshot_to_json() {
+        let mut pool = AllocatorPool::new();
+        let a = pool.new_allocator();
+        let p = a.malloc(4096);
+
+        let json = serde_json::to_value(pool.snapshot()).unwrap();
+        for key in [
+            "elapsed_msecs",
+            "current_rss",
+            "peak_rss",
+            "current_commit",
+            "peak_commit",
+            "page_faults",
+            "pool_used",
+            "pool_committed",
+            "heap_count",
+        ] {
+            assert!(json[key].is_u64(), "missing {key}");
+        }
+        assert_eq!(json["heap_count"], 1);
+        assert!(json["pool_used"].as_u64().unwrap() >= 4096);
+        a.free(p);
+    }
+
+    #[test]
+    fn sync_pool_across_threads() {
+        let pool = Arc::new(SyncAllocatorPool::new());
+
+        let handles: Vec<_> = (0..8)
+            .map(|tid| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    // only allocate from a heap created on this thread
+                    let alloc = pool.new_allocator();
+                    assert_eq!(alloc.owner(), std::thread::current().id());
+                    for size in [16, 256, 4096] {
+                        let p = alloc.malloc(size);
+                        assert!(!p.is_null());
+                        unsafe { p.write_bytes(tid as u8, size) };
+                        alloc.free(p);
+                    }
+                    alloc.id()
+                })
+            })
+            .collect();
+
+        let mut ids: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 8);
+    }
+
+    #[test]
+    fn named_allocator() {
+        let mut pool = AllocatorPool::new();
+        let alloc = pool.new_named_allocator("parser");
+        assert_eq!(alloc.name(), Some("parser"));
+        assert!(format!("{alloc:?}").contains("\"parser\""));
+
+        assert_eq!(pool.new_allocator().name(), None);
+        assert_eq!(alloc.id(), 1);
+    }
+
+    #[test]
+    fn init_process_twice() {
+        init_process();
+        init_process();
+
+        let alloc = Allocator::default();
+        let p = alloc.malloc(128);
+        assert!(!p.is_null());
+        alloc.free(p);
+    }
+
+    #[test]
+    fn linked_version() {
+        let v = version();
+        assert_ne!(
+            v,
+            Version {
+                major: 0,
+                minor: 0,
+                patch: 0
+            }
+        );
+
+        let text = v.to_string();
+        assert_eq!(text, format!("{}.{}.{}", v.major, v.minor, v.patch));
+        assert_eq!(text.split('.').count(), 3);
+    }
+
+    #[test]
+    fn reset_for_test_restores_options() {
+        use cesium_libmimalloc_sys::{
+            mi_option_eager_commit_delay,
+            options::{
+                mi_option_get,
+                mi_option_set,
+            },
+        };
+
+        // resetting the stats would skew them for the tests running alongside
+        if !is_isolated() {
+            assert!(run_isolated("tests::reset_for_test_restores_options"));
+            return;
         }
+
+        reset_for_test();
+        let default = unsafe { mi_option_get(mi_option_eager_commit_delay) };
+
+        unsafe { mi_option_set(mi_option_eager_commit_delay, default + 3) };
+        let alloc = allocator::Allocator::default();
+        let p = alloc.malloc(32 << 20);
+        assert!(!p.is_null());
+        stats::merge();
+        assert!(stat_columns(&stats::capture_stats(), "segments")[1] >= 1.0);
+        alloc.free(p);
+
+        reset_for_test();
+        assert_eq!(
+            unsafe { mi_option_get(mi_option_eager_commit_delay) },
+            default
+        );
+        assert_eq!(stat_columns(&stats::capture_stats(), "segments"), [0.0; 4]);
     }
 }
@@ -0,0 +1,532 @@
+use std::ffi::c_long;
+
+use cesium_libmimalloc_sys::{
+    self as mi,
+    _mi_option_last,
+    mi_option_t,
+    options::{
+        mi_option_get,
+        mi_option_is_enabled,
+        mi_option_set,
+        mi_option_set_default,
+        mi_option_set_enabled,
+        mi_option_set_enabled_default,
+    },
+};
+
+use crate::error::WrongOptionKind;
+
+/// A typed mimalloc runtime option.
+///
+/// Options are either boolean (enabled or disabled) or numeric, see
+/// [`is_boolean`](OptionName::is_boolean).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OptionName {
+    /// Print error messages to `stderr`.
+    ShowErrors,
+    /// Print statistics to `stderr` when the program is done.
+    ShowStats,
+    /// Print verbose messages to `stderr`.
+    Verbose,
+    /// (experimental) Use large OS pages (2MiB in size) if possible.
+    LargeOsPages,
+    /// (experimental) The number of huge OS pages (1GiB in size) to reserve at
+    /// the start of the program.
+    ReserveHugeOsPages,
+    /// (experimental) Reserve huge OS pages at this NUMA node.
+    ReserveHugeOsPagesAt,
+    /// (experimental) Reserve this amount of OS memory at startup, in KiB.
+    ReserveOsMemory,
+    /// (experimental) The first N segments per thread are not eagerly
+    /// committed.
+    EagerCommitDelay,
+    /// (experimental) Pretend there are at most N NUMA nodes, 0 uses the
+    /// detected number.
+    UseNumaNodes,
+    /// (experimental) Do not use OS memory for allocation, only pre-reserved
+    /// arenas.
+    LimitOsAlloc,
+    /// (experimental) OS tag to assign to mimalloc'd memory.
+    OsTag,
+    /// (experimental) Maximum number of error messages to show.
+    MaxErrors,
+    /// (experimental) Maximum number of warnings to show.
+    MaxWarnings,
+    /// (experimental) Maximum number of segments to reclaim.
+    MaxSegmentReclaim,
+}
+
+impl OptionName {
+    /// Every known option.
+    pub const ALL: [OptionName; 14] = [
+        OptionName::ShowErrors,
+        OptionName::ShowStats,
+        OptionName::Verbose,
+        OptionName::LargeOsPages,
+        OptionName::ReserveHugeOsPages,
+        OptionName::ReserveHugeOsPagesAt,
+        OptionName::ReserveOsMemory,
+        OptionName::EagerCommitDelay,
+        OptionName::UseNumaNodes,
+        OptionName::LimitOsAlloc,
+        OptionName::OsTag,
+        OptionName::MaxErrors,
+        OptionName::MaxWarnings,
+        OptionName::MaxSegmentReclaim,
+    ];
+
+    /// The raw `mi_option_t` value of this option.
+    pub fn raw(self) -> mi_option_t {
+        match self {
+            | OptionName::ShowErrors => mi::mi_option_show_errors,
+            | OptionName::ShowStats => mi::mi_option_show_stats,
+            | OptionName::Verbose => mi::mi_option_verbose,
+            | OptionName::LargeOsPages => mi::mi_option_large_os_pages,
+            | OptionName::ReserveHugeOsPages => mi::mi_option_reserve_huge_os_pages,
+            | OptionName::ReserveHugeOsPagesAt => mi::mi_option_reserve_huge_os_pages_at,
+            | OptionName::ReserveOsMemory => mi::mi_option_reserve_os_memory,
+            | OptionName::EagerCommitDelay => mi::mi_option_eager_commit_delay,
+            | OptionName::UseNumaNodes => mi::mi_option_use_numa_nodes,
+            | OptionName::LimitOsAlloc => mi::mi_option_limit_os_alloc,
+            | OptionName::OsTag => mi::mi_option_os_tag,
+            | OptionName::MaxErrors => mi::mi_option_max_errors,
+            | OptionName::MaxWarnings => mi::mi_option_max_warnings,
+            | OptionName::MaxSegmentReclaim => mi::mi_option_max_segment_reclaim,
+        }
+    }
+
+    /// Whether this option is enabled or disabled rather than numeric.
+    pub fn is_boolean(self) -> bool {
+        matches!(
+            self,
+            OptionName::ShowErrors |
+                OptionName::ShowStats |
+                OptionName::Verbose |
+                OptionName::LargeOsPages |
+                OptionName::LimitOsAlloc
+        )
+    }
+}
+
+/// Returns true if the option is enabled.
+///
+/// Note: this function is not thread safe.
+pub fn is_enabled(option: OptionName) -> bool {
+    unsafe { mi_option_is_enabled(option.raw()) }
+}
+
+/// Returns the value of the option.
+///
+/// The value of boolean options is 1 or 0.
+///
+/// Note: this function is not thread safe.
+pub fn get(option: OptionName) -> i64 {
+    unsafe { mi_option_get(option.raw()) as i64 }
+}
+
+/// Enable or disable a boolean option.
+///
+/// Returns an error, leaving the option alone, if `option` is numeric.
+///
+/// Note: this function is not thread safe.
+pub fn set_enabled(option: OptionName, enable: bool) -> Result<(), WrongOptionKind> {
+    if !option.is_boolean() {
+        return Err(WrongOptionKind(option));
+    }
+
+    unsafe { mi_option_set_enabled(option.raw(), enable) };
+    Ok(())
+}
+
+/// Returns the value of a numeric option.
+///
+/// Like [`get`], boolean options read as 1 or 0.
+///
+/// Note: this function is not thread safe.
+pub fn get_numeric(option: OptionName) -> i64 {
+    get(option)
+}
+
+/// Set the value of a numeric option.
+///
+/// Returns an error, leaving the option alone, if `option` is boolean.
+///
+/// Note: this function is not thread safe.
+pub fn set_numeric(option: OptionName, value: i64) -> Result<(), WrongOptionKind> {
+    if option.is_boolean() {
+        return Err(WrongOptionKind(option));
+    }
+
+    unsafe { mi_option_set(option.raw(), value as c_long) };
+    Ok(())
+}
+
+/// Set the value of an option, unless it was already set.
+///
+/// Options set through the environment (`MIMALLOC_*`) or explicitly, e.g.
+/// with [`set_numeric`], are left alone. This lets libraries suggest a value
+/// without overriding the user's configuration.
+///
+/// Note: this function is not thread safe.
+pub fn set_default(option: OptionName, value: i64) {
+    unsafe { mi_option_set_default(option.raw(), value as c_long) }
+}
+
+/// Enable or disable an option, unless it was already set.
+///
+/// See [`set_default`].
+///
+/// Note: this function is not thread safe.
+pub fn set_enabled_default(option: OptionName, enable: bool) {
+    unsafe { mi_option_set_enabled_default(option.raw(), enable) }
+}
+
+/// The values of every mimalloc option at some point, see
+/// [`snapshot_options`].
+///
+/// Snapshots are equal if the options have the same values, whether or not
+/// they were set explicitly.
+#[derive(Debug, Clone)]
+pub struct OptionSnapshot {
+    values: Vec<(mi_option_t, c_long)>,
+    explicit: Vec<bool>,
+}
+
+impl PartialEq for OptionSnapshot {
+    fn eq(&self, other: &Self) -> bool {
+        self.values == other.values
+    }
+}
+
+impl Eq for OptionSnapshot {}
+
+/// Read the values of every mimalloc option, including those without an
+/// [`OptionName`], to put them back later with [`restore_options`].
+///
+/// Also records which options were set explicitly or through the environment,
+/// so that restoring leaves the others as defaults.
+///
+/// Note: this function is not thread safe.
+pub fn snapshot_options() -> OptionSnapshot {
+    let values: Vec<_> = (0.._mi_option_last)
+        .map(|option| (option, unsafe { mi_option_get(option) }))
+        .collect();
+    let explicit = values
+        .iter()
+        .map(|(option, _)| is_explicit(*option))
+        .collect();
+
+    OptionSnapshot { values, explicit }
+}
+
+/// Set every option back to its value in `snap`.
+///
+/// Options which were defaults when the snapshot was taken are restored as
+/// defaults, so [`set_default`] keeps working on them. mimalloc can't unset
+/// an option though: one set explicitly since the snapshot is restored to
+/// its old value but stays explicitly set.
+///
+/// Note: this function is not thread safe.
+pub fn restore_options(snap: &OptionSnapshot) {
+    for (&(option, value), &explicit) in snap.values.iter().zip(&snap.explicit) {
+        if explicit || is_explicit(option) {
+            if unsafe { mi_option_get(option) } != value {
+                unsafe { mi_option_set(option, value) };
+            }
+        } else {
+            unsafe { mi_option_set_default(option, value) };
+        }
+    }
+}
+
+/// Whether `option` was set explicitly or through the environment rather
+/// than being a default.
+///
+/// mimalloc doesn't expose this, so it's probed by suggesting another default
+/// value, which only takes on default options, and putting the value back.
+fn is_explicit(option: mi_option_t) -> bool {
+    unsafe {
+        let value = mi_option_get(option);
+        mi_option_set_default(option, value.wrapping_add(1));
+        let explicit = mi_option_get(option) == value;
+        mi_option_set_default(option, value);
+        explicit
+    }
+}
+
+/// Restores every option to its value at creation when dropped, e.g. to tune
+/// options for the length of a benchmark.
+///
+/// # Example
+///
+/// ```no_run
+/// use cesium_allocator::options::{
+///     OptionGuard,
+///     OptionsBuilder,
+/// };
+///
+/// {
+///     let _guard = OptionGuard::new();
+///     OptionsBuilder::new().eager_commit_delay(4).build();
+///     // ...
+/// }
+/// // the eager commit delay is back to what it was
+/// ```
+#[must_use = "options are restored when the guard is dropped"]
+pub struct OptionGuard {
+    snapshot: OptionSnapshot,
+}
+
+impl OptionGuard {
+    /// Snapshot the options, see [`snapshot_options`].
+    pub fn new() -> Self {
+        OptionGuard {
+            snapshot: snapshot_options(),
+        }
+    }
+}
+
+impl Default for OptionGuard {
+    fn default() -> Self {
+        OptionGuard::new()
+    }
+}
+
+impl Drop for OptionGuard {
+    fn drop(&mut self) {
+        restore_options(&self.snapshot);
+    }
+}
+
+/// Collects option values and applies them all at once.
+///
+/// # Example
+///
+/// ```no_run
+/// use cesium_allocator::options::OptionsBuilder;
+///
+/// OptionsBuilder::new()
+///     .show_stats(true)
+///     .reserve_huge_os_pages(4)
+///     .build();
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct OptionsBuilder {
+    values: Vec<(OptionName, i64)>,
+}
+
+impl OptionsBuilder {
+    /// Create a builder which doesn't change any options.
+    pub fn new() -> Self {
+        OptionsBuilder::default()
+    }
+
+    /// Print error messages to `stderr`.
+    pub fn show_errors(self, enable: bool) -> Self {
+        self.value(OptionName::ShowErrors, enable as i64)
+    }
+
+    /// Print statistics to `stderr` when the program is done.
+    pub fn show_stats(self, enable: bool) -> Self {
+        self.value(OptionName::ShowStats, enable as i64)
+    }
+
+    /// Print verbose messages to `stderr`.
+    pub fn verbose(self, enable: bool) -> Self {
+        self.value(OptionName::Verbose, enable as i64)
+    }
+
+    /// Use large OS pages (2MiB in size) if possible.
+    pub fn large_os_pages(self, enable: bool) -> Self {
+        self.value(OptionName::LargeOsPages, enable as i64)
+    }
+
+    /// The number of huge OS pages (1GiB in size) to reserve at the start of
+    /// the program.
+    pub fn reserve_huge_os_pages(self, pages: i64) -> Self {
+        self.value(OptionName::ReserveHugeOsPages, pages)
+    }
+
+    /// Reserve the huge OS pages at this NUMA node.
+    pub fn reserve_huge_os_pages_at(self, node: i64) -> Self {
+        self.value(OptionName::ReserveHugeOsPagesAt, node)
+    }
+
+    /// Reserve this amount of OS memory at startup, in KiB.
+    pub fn reserve_os_memory(self, kib: i64) -> Self {
+        self.value(OptionName::ReserveOsMemory, kib)
+    }
+
+    /// The first N segments per thread are not eagerly committed.
+    pub fn eager_commit_delay(self, segments: i64) -> Self {
+        self.value(OptionName::EagerCommitDelay, segments)
+    }
+
+    /// Pretend there are at most N NUMA nodes, 0 uses the detected number.
+    pub fn use_numa_nodes(self, nodes: i64) -> Self {
+        self.value(OptionName::UseNumaNodes, nodes)
+    }
+
+    /// Do not use OS memory for allocation, only pre-reserved arenas.
+    pub fn limit_os_alloc(self, enable: bool) -> Self {
+        self.value(OptionName::LimitOsAlloc, enable as i64)
+    }
+
+    /// OS tag to assign to mimalloc'd memory.
+    pub fn os_tag(self, tag: i64) -> Self {
+        self.value(OptionName::OsTag, tag)
+    }
+
+    /// Maximum number of error messages to show.
+    pub fn max_errors(self, max: i64) -> Self {
+        self.value(OptionName::MaxErrors, max)
+    }
+
+    /// Maximum number of warnings to show.
+    pub fn max_warnings(self, max: i64) -> Self {
+        self.value(OptionName::MaxWarnings, max)
+    }
+
+    /// Maximum number of segments to reclaim.
+    pub fn max_segment_reclaim(self, max: i64) -> Self {
+        self.value(OptionName::MaxSegmentReclaim, max)
+    }
+
+    /// Apply every collected option, in the order they were given.
+    ///
+    /// Note: options are not thread safe, so this shouldn't race with other
+    /// threads reading or writing them.
+    pub fn build(self) {
+        for (option, value) in self.values {
+            unsafe {
+                if option.is_boolean() {
+                    mi_option_set_enabled(option.raw(), value != 0);
+                } else {
+                    mi_option_set(option.raw(), value as c_long);
+                }
+            }
+        }
+    }
+
+    fn value(mut self, option: OptionName, value: i64) -> Self {
+        self.values.push((option, value));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_round_trip() {
+        let _options = crate::lock_options();
+        let max_warnings = get(OptionName::MaxWarnings);
+        let show_errors = is_enabled(OptionName::ShowErrors);
+
+        OptionsBuilder::new()
+            .max_warnings(max_warnings + 5)
+            .show_errors(!show_errors)
+            .build();
+        assert_eq!(get(OptionName::MaxWarnings), max_warnings + 5);
+        assert_eq!(is_enabled(OptionName::ShowErrors), !show_errors);
+
+        OptionsBuilder::new()
+            .max_warnings(max_warnings)
+            .show_errors(show_errors)
+            .build();
+        assert_eq!(get(OptionName::MaxWarnings), max_warnings);
+        assert_eq!(is_enabled(OptionName::ShowErrors), show_errors);
+    }
+
+    #[test]
+    fn numeric_round_trip() {
+        let _options = crate::lock_options();
+        let delay = get_numeric(OptionName::EagerCommitDelay);
+
+        set_numeric(OptionName::EagerCommitDelay, 4).unwrap();
+        assert_eq!(get_numeric(OptionName::EagerCommitDelay), 4);
+
+        set_numeric(OptionName::EagerCommitDelay, delay).unwrap();
+        assert_eq!(get_numeric(OptionName::EagerCommitDelay), delay);
+    }
+
+    #[test]
+    fn guard_restores_options() {
+        let _options = crate::lock_options();
+        let before = snapshot_options();
+
+        {
+            let _guard = OptionGuard::new();
+            OptionsBuilder::new()
+                .max_warnings(get(OptionName::MaxWarnings) + 5)
+                .eager_commit_delay(get(OptionName::EagerCommitDelay) + 2)
+                .show_errors(!is_enabled(OptionName::ShowErrors))
+                .build();
+            assert_ne!(snapshot_options(), before);
+        }
+
+        assert_eq!(snapshot_options(), before);
+    }
+
+    #[test]
+    fn defaults_apply_after_guard() {
+        if !crate::is_isolated() {
+            assert!(crate::run_isolated(
+                "options::tests::defaults_apply_after_guard"
+            ));
+            return;
+        }
+
+        let reclaim = get(OptionName::MaxSegmentReclaim);
+        {
+            let _guard = OptionGuard::new();
+            set_numeric(OptionName::EagerCommitDelay, 4).unwrap();
+            set_default(OptionName::MaxSegmentReclaim, reclaim + 3);
+            assert_eq!(get(OptionName::MaxSegmentReclaim), reclaim + 3);
+        }
+        assert_eq!(get(OptionName::MaxSegmentReclaim), reclaim);
+
+        set_default(OptionName::MaxSegmentReclaim, reclaim + 7);
+        assert_eq!(get(OptionName::MaxSegmentReclaim), reclaim + 7);
+    }
+
+    #[test]
+    fn defaults_dont_override() {
+        let _options = crate::lock_options();
+        let max_warnings = get(OptionName::MaxWarnings);
+        let show_errors = is_enabled(OptionName::ShowErrors);
+
+        set_numeric(OptionName::MaxWarnings, 7).unwrap();
+        set_default(OptionName::MaxWarnings, 9);
+        assert_eq!(get(OptionName::MaxWarnings), 7);
+
+        set_enabled(OptionName::ShowErrors, !show_errors).unwrap();
+        set_enabled_default(OptionName::ShowErrors, show_errors);
+        assert_eq!(is_enabled(OptionName::ShowErrors), !show_errors);
+
+        set_numeric(OptionName::MaxWarnings, max_warnings).unwrap();
+        set_enabled(OptionName::ShowErrors, show_errors).unwrap();
+    }
+
+    #[test]
+    fn option_kind_is_checked() {
+        assert_eq!(
+            set_numeric(OptionName::ShowStats, 1),
+            Err(WrongOptionKind(OptionName::ShowStats))
+        );
+        assert_eq!(
+            set_enabled(OptionName::EagerCommitDelay, true),
+            Err(WrongOptionKind(OptionName::EagerCommitDelay))
+        );
+    }
+
+    #[test]
+    fn raw_values_are_distinct() {
+        for (i, a) in OptionName::ALL.iter().enumerate() {
+            for b in &OptionName::ALL[i + 1..] {
+                assert_ne!(a.raw(), b.raw());
+            }
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use cesium_libmimalloc_sys::{
+    allocator::{
+        mi_reserve_huge_os_pages_at,
+        mi_reserve_huge_os_pages_interleave,
+        mi_reserve_os_memory,
+        mi_reserve_os_memory_ex,
+    },
+    mi_arena_id_t,
+    mi_option_limit_os_alloc,
+    options::mi_option_set_enabled,
+};
+
+use crate::error::{
+    AllocError,
+    ParseSizeError,
+    ReserveError,
+};
+
+/// Reserve `pages` of huge OS pages (1GiB) for mimalloc to allocate from.
+///
+/// With a `numa_node` all pages are reserved at that node, otherwise they're
+/// divided evenly over the detected NUMA nodes. Reserving stops after at most
+/// `timeout_msecs`, as reserving contiguous memory can take a long time when
+/// physical memory is fragmented.
+///
+/// Returns the mimalloc error code on failure: `ENOMEM` if running out of
+/// memory, or `ETIMEDOUT` if timed out.
+pub fn reserve_huge_os_pages(
+    pages: usize,
+    numa_node: Option<i32>,
+    timeout_msecs: usize,
+) -> Result<(), i32> {
+    let code = unsafe {
+        match numa_node {
+            | Some(node) => mi_reserve_huge_os_pages_at(pages, node, timeout_msecs),
+            | None => mi_reserve_huge_os_pages_interleave(pages, 0, timeout_msecs),
+        }
+    };
+
+    match code {
+        | 0 => Ok(()),
+        | code => Err(code),
+    }
+}
+
+/// Reserve `size` bytes of OS memory as a new arena, returning its id.
+///
+/// With `exclusive`, only heaps created in this arena (see
+/// [`AllocatorPool::new_allocator_in_arena`](crate::AllocatorPool::new_allocator_in_arena))
+/// allocate out of it, which confines a subsystem's allocations to memory set
+/// aside for it. With `commit`, the memory is committed upfront.
+///
+/// Returns the mimalloc error code on failure (e.g. `ENOMEM`).
+pub fn reserve_arena(size: usize, commit: bool, exclusive: bool) -> Result<mi_arena_id_t, i32> {
+    let mut arena_id: mi_arena_id_t = 0;
+    let code = unsafe { mi_reserve_os_memory_ex(size, commit, false, exclusive, &mut arena_id) };
+
+    match code {
+        | 0 => Ok(arena_id),
+        | code => Err(code),
+    }
+}
+
+/// Reserve OS memory for mimalloc to allocate from, sized by a human-readable
+/// string such as `"1g"` or `"512m"` (see [`parse_size`]).
+///
+/// Unlike [`reserve_arena`] the memory isn't exclusive, and any heap can
+/// allocate out of it.
+pub fn reserve_os_memory(spec: &str) -> Result<(), ReserveError> {
+    let size = parse_size(spec)?;
+
+    match unsafe { mi_reserve_os_memory(size, false, true) } {
+        | 0 => Ok(()),
+        | code => Err(ReserveError::Alloc(AllocError::from_code(code))),
+    }
+}
+
+/// Parse a human-readable byte size: an unsigned integer, optionally followed
+/// by a `k`, `m` or `g` suffix (case-insensitive) for KiB, MiB or GiB.
+///
+/// Surrounding whitespace is ignored, so `" 64K "` is 65536 bytes.
+pub fn parse_size(spec: &str) -> Result<usize, ParseSizeError> {
+    let spec = spec.trim();
+    let Some(last) = spec.chars().last() else {
+        return Err(ParseSizeError::Empty);
+    };
+
+    let (number, shift) = if last.is_ascii_digit() {
+        (spec, 0)
+    } else {
+        let shift = match last.to_ascii_lowercase() {
+            | 'k' => 10,
+            | 'm' => 20,
+            | 'g' => 30,
+            | _ => return Err(ParseSizeError::InvalidSuffix),
+        };
+        (&spec[..spec.len() - 1], shift)
+    };
+
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ParseSizeError::InvalidNumber);
+    }
+
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or(ParseSizeError::Overflow)
+}
+
+/// Only allocate out of pre-reserved arenas, never asking the OS for more
+/// memory.
+///
+/// For sandboxed or embedded deployments with a fixed memory budget: reserve
+/// it upfront with [`reserve_arena`] or [`reserve_huge_os_pages`], then
+/// enable this so allocations fail once the reservation is used up.
+///
+/// Note: options are not thread safe, so this shouldn't race with other
+/// threads reading or writing them.
+pub fn set_arena_only(enabled: bool) {
+    unsafe { mi_option_set_enabled(mi_option_limit_os_alloc, enabled) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_human_sizes() {
+        assert_eq!(parse_size("1g"), Ok(1073741824));
+        assert_eq!(parse_size("512m"), Ok(512 << 20));
+        assert_eq!(parse_size(" 64K "), Ok(64 << 10));
+        assert_eq!(parse_size("4096"), Ok(4096));
+
+        assert_eq!(parse_size(""), Err(ParseSizeError::Empty));
+        assert_eq!(parse_size("abc"), Err(ParseSizeError::InvalidSuffix));
+        assert_eq!(parse_size("12x"), Err(ParseSizeError::InvalidSuffix));
+        assert_eq!(parse_size("g"), Err(ParseSizeError::InvalidNumber));
+        assert_eq!(parse_size("-1k"), Err(ParseSizeError::InvalidNumber));
+        assert_eq!(
+            parse_size("99999999999999999999g"),
+            Err(ParseSizeError::Overflow)
+        );
+    }
+
+    #[test]
+    fn reserve_os_memory_from_string() {
+        assert_eq!(reserve_os_memory("4m"), Ok(()));
+        assert_eq!(
+            reserve_os_memory("4x"),
+            Err(ReserveError::InvalidSize(ParseSizeError::InvalidSuffix))
+        );
+    }
+
+    #[test]
+    fn reserve_no_pages() {
+        assert_eq!(reserve_huge_os_pages(0, None, 10), Ok(()));
+    }
+
+    #[test]
+    fn heap_in_reserved_arena() {
+        let arena = reserve_arena(64 << 20, false, true).unwrap();
+
+        let mut pool = crate::AllocatorPool::new();
+        let alloc = pool.new_allocator_in_arena(arena).unwrap();
+
+        let p = alloc.malloc(4096);
+        assert!(!p.is_null());
+        assert!(alloc.contains_block(p));
+        alloc.free(p);
+    }
+
+    #[test]
+    fn arena_only_allocates_from_reservation() {
+        if !crate::is_isolated() {
+            assert!(crate::run_isolated(
+                "reserve::tests::arena_only_allocates_from_reservation"
+            ));
+            return;
+        }
+
+        reserve_arena(64 << 20, true, false).unwrap();
+        set_arena_only(true);
+
+        let mut pool = crate::AllocatorPool::new();
+        let alloc = pool.new_allocator();
+        let p = alloc.malloc(1 << 20);
+        assert!(!p.is_null());
+        alloc.free(p);
+
+        set_arena_only(false);
+    }
+}
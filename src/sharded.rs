@@ -0,0 +1,243 @@
+use std::{
+    cell::RefCell,
+    ffi::c_void,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+        PoisonError,
+        Weak,
+    },
+    thread,
+};
+
+use cesium_libmimalloc_sys::{
+    allocator::{
+        mi_free,
+        mi_malloc,
+    },
+    heap::mi_heap_new,
+};
+
+use crate::allocator::Allocator;
+
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+type Slot = Mutex<Option<Arc<Allocator>>>;
+
+thread_local! {
+    static THREAD_INDEX: usize = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+    static OWNED_SHARDS: RefCell<Vec<ShardGuard>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Empties the slot of a shard created by this thread when the thread exits.
+///
+/// mimalloc deletes the heaps of a thread when it exits, so the shard must not
+/// be handed out afterwards. Thread-local destructors run before mimalloc's
+/// own thread teardown.
+struct ShardGuard(Weak<Slot>);
+
+impl Drop for ShardGuard {
+    fn drop(&mut self) {
+        let Some(slot) = self.0.upgrade() else {
+            return;
+        };
+
+        let mut slot = slot.lock().unwrap_or_else(PoisonError::into_inner);
+        if slot
+            .as_ref()
+            .is_some_and(|shard| shard.owner() == thread::current().id())
+        {
+            slot.take();
+        }
+    }
+}
+
+/// A set of heaps behind a single allocation API, spreading threads over them
+/// to reduce contention.
+///
+/// Threads are assigned shards round-robin. As a mimalloc heap may only be
+/// allocated from by the thread that created it, each shard's heap is created
+/// by the first thread routed to it, and the shard then belongs to that
+/// thread. With more threads than shards, a thread whose shard belongs to
+/// another thread allocates from its own default heap instead. When the owner
+/// thread exits its shard is emptied, and the next thread routed to it
+/// creates a new heap.
+///
+/// Blocks are always freed with `mi_free`, so freeing a block on any thread,
+/// whichever shard it came from, is fine.
+pub struct ShardedAllocator {
+    shards: Vec<Arc<Slot>>,
+}
+
+impl ShardedAllocator {
+    /// Create an allocator with `shards` shards, whose heaps are created on
+    /// first use.
+    ///
+    /// A `shards` of 0 is treated as 1.
+    pub fn new(shards: usize) -> Self {
+        ShardedAllocator {
+            shards: (0..shards.max(1))
+                .map(|_| Arc::new(Mutex::new(None)))
+                .collect(),
+        }
+    }
+
+    /// The number of shards.
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Always `false`, there's at least one shard.
+    pub fn is_empty(&self) -> bool {
+        self.shards.is_empty()
+    }
+
+    /// The shard the current thread allocates from, or `None` if its shard
+    /// belongs to another thread.
+    ///
+    /// Shard ids are their index plus one. The shard's heap is deleted when
+    /// the current thread exits, so the returned allocator must not be kept
+    /// past that.
+    pub fn current_shard(&self) -> Option<Arc<Allocator>> {
+        let index = THREAD_INDEX.with(|index| *index) % self.shards.len();
+        let mut slot = self.shards[index]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let shard = match &*slot {
+            | Some(shard) => shard.clone(),
+            | None => {
+                // during thread teardown the guard can't be registered anymore
+                let guard = ShardGuard(Arc::downgrade(&self.shards[index]));
+                OWNED_SHARDS
+                    .try_with(|guards| guards.borrow_mut().push(guard))
+                    .ok()?;
+
+                let shard = Arc::new(Allocator::new(index as u32 + 1, unsafe { mi_heap_new() }));
+                *slot = Some(shard.clone());
+                shard
+            },
+        };
+
+        (shard.owner() == thread::current().id()).then_some(shard)
+    }
+
+    /// Allocate `size` bytes from the current thread's shard.
+    ///
+    /// Returns null if out of memory.
+    pub fn alloc(&self, size: usize) -> *mut u8 {
+        match self.current_shard() {
+            | Some(shard) => shard.malloc(size),
+            | None => unsafe { mi_malloc(size) as *mut u8 },
+        }
+    }
+
+    /// Free `p`, which may come from any shard.
+    pub fn free(&self, p: *mut u8) {
+        unsafe { mi_free(p as *mut c_void) }
+    }
+
+    /// The shards currently in use, whose owner threads are still running.
+    pub fn shards(&self) -> Vec<Arc<Allocator>> {
+        self.shards
+            .iter()
+            .filter_map(|slot| slot.lock().unwrap_or_else(PoisonError::into_inner).clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+
+    use super::*;
+
+    // threads are only spread evenly if no other test assigns indices
+    // concurrently
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn threads_use_different_shards() {
+        let _serial = SERIAL.lock().unwrap_or_else(PoisonError::into_inner);
+        let sharded = Arc::new(ShardedAllocator::new(4));
+        let barrier = Arc::new(Barrier::new(5));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let sharded = sharded.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let p = sharded.alloc(64);
+                    assert!(!p.is_null());
+
+                    let shard = sharded.current_shard().unwrap();
+                    assert!(shard.contains_block(p));
+                    let id = shard.id();
+                    sharded.free(p);
+                    drop(shard);
+
+                    // keep the shard alive until the main thread has looked
+                    barrier.wait();
+                    barrier.wait();
+                    id
+                })
+            })
+            .collect();
+
+        barrier.wait();
+        let mut ids: Vec<_> = sharded.shards().iter().map(|shard| shard.id()).collect();
+        barrier.wait();
+        ids.sort_unstable();
+        assert_eq!(ids, [1, 2, 3, 4]);
+
+        let mut ids: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn exited_owner_empties_shard() {
+        let _serial = SERIAL.lock().unwrap_or_else(PoisonError::into_inner);
+        let sharded = Arc::new(ShardedAllocator::new(1));
+
+        let other = sharded.clone();
+        let p = thread::spawn(move || {
+            let shard = other.current_shard().unwrap();
+            assert_eq!(shard.owner(), thread::current().id());
+            shard.malloc(64) as usize
+        })
+        .join()
+        .unwrap() as *mut u8;
+
+        // the heap of the exited thread is gone and must not be handed out
+        assert!(sharded.shards().is_empty());
+
+        let shard = sharded.current_shard().unwrap();
+        assert_eq!(shard.owner(), thread::current().id());
+        assert!(!shard.contains_block(p));
+        sharded.free(p);
+    }
+
+    #[test]
+    fn foreign_shard_falls_back() {
+        let _serial = SERIAL.lock().unwrap_or_else(PoisonError::into_inner);
+        let sharded = Arc::new(ShardedAllocator::new(1));
+        let owner = sharded.current_shard().map(|shard| shard.owner());
+
+        let other = sharded.clone();
+        thread::spawn(move || {
+            assert!(other.current_shard().is_none());
+            let p = other.alloc(64);
+            assert!(!p.is_null());
+            other.free(p);
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(owner, Some(thread::current().id()));
+    }
+}
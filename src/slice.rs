@@ -0,0 +1,139 @@
+use std::{
+    fmt,
+    mem::ManuallyDrop,
+    ops::{
+        Deref,
+        DerefMut,
+    },
+    ptr::{
+        self,
+        NonNull,
+    },
+};
+
+use crate::allocator::Allocator;
+
+/// Types for which the all-zero bit pattern is a valid value.
+///
+/// With the `bytemuck` feature, `bytemuck::Zeroable` types can also be
+/// allocated zeroed without implementing this, through the `_bytemuck`
+/// variants such as `Allocator::alloc_zeroed_box_bytemuck`.
+///
+/// # Safety
+///
+/// Implementing this for a type where zeroed memory is not a valid value (such
+/// as references, `NonNull`, or most enums) is undefined behaviour.
+pub unsafe trait Zeroable {}
+
+macro_rules! impl_zeroable {
+    ($($t:ty),* $(,)?) => {
+        $(unsafe impl Zeroable for $t {})*
+    };
+}
+
+impl_zeroable!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+);
+
+unsafe impl<T: Zeroable, const N: usize> Zeroable for [T; N] {}
+unsafe impl<T> Zeroable for std::mem::MaybeUninit<T> {}
+unsafe impl<T> Zeroable for *const T {}
+unsafe impl<T> Zeroable for *mut T {}
+unsafe impl<T> Zeroable for Option<NonNull<T>> {}
+
+/// A slice of `T` allocated out of an [`Allocator`].
+///
+/// Derefs to `[T]`, and drops its elements and frees the allocation when
+/// dropped. Slices of zero-sized types aren't allocated at all.
+pub struct HeapSlice<'a, T> {
+    ptr: NonNull<T>,
+    len: usize,
+    alloc: &'a Allocator,
+}
+
+impl<'a, T> HeapSlice<'a, T> {
+    /// Take ownership of `len` initialized elements at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by `alloc`, be aligned for `T` and hold
+    /// `len` initialized values of `T`.
+    pub(crate) unsafe fn from_raw_parts(alloc: &'a Allocator, ptr: NonNull<T>, len: usize) -> Self {
+        HeapSlice { ptr, len, alloc }
+    }
+
+    /// Give up ownership of the elements and allocation without dropping or
+    /// freeing them.
+    pub(crate) fn into_raw_parts(self) -> (NonNull<T>, usize) {
+        let this = ManuallyDrop::new(self);
+        (this.ptr, this.len)
+    }
+
+    /// Set the number of initialized elements.
+    ///
+    /// # Safety
+    ///
+    /// The first `len` elements must be initialized, and the allocation must
+    /// hold at least `len` elements.
+    pub(crate) unsafe fn set_len(&mut self, len: usize) {
+        self.len = len;
+    }
+
+    /// The allocator this slice was allocated from.
+    pub fn allocator(&self) -> &'a Allocator {
+        self.alloc
+    }
+
+    /// A raw pointer to the start of the slice.
+    pub fn as_ptr(&self) -> *const T {
+        self.ptr.as_ptr()
+    }
+
+    /// A raw mutable pointer to the start of the slice.
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+}
+
+impl<T> Deref for HeapSlice<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> DerefMut for HeapSlice<'_, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for HeapSlice<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for HeapSlice<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(ptr::slice_from_raw_parts_mut(self.ptr.as_ptr(), self.len)) };
+        self.alloc.free_array(self.ptr);
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    ffi::{
+        c_char,
+        c_void,
+        CStr,
+    },
+    ptr,
+};
+
+use cesium_libmimalloc_sys::allocator::{
+    mi_process_info,
+    mi_stats_merge,
+    mi_stats_print_out,
+    mi_stats_reset,
+};
+
+/// Process information (time and memory usage) as reported by mimalloc.
+///
+/// See [`process_info`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessInfo {
+    /// Elapsed wall-clock time of the process in milli-seconds.
+    pub elapsed_msecs: usize,
+    /// User time in milli-seconds (as the sum over all threads).
+    pub user_msecs: usize,
+    /// System time in milli-seconds.
+    pub system_msecs: usize,
+    /// Current working set size (touched pages).
+    pub current_rss: usize,
+    /// Peak working set size (touched pages).
+    pub peak_rss: usize,
+    /// Current committed memory (backed by the page file).
+    pub current_commit: usize,
+    /// Peak committed memory (backed by the page file).
+    pub peak_commit: usize,
+    /// Count of hard page faults.
+    pub page_faults: usize,
+}
+
+/// Process information combined with the usage of an allocator pool.
+///
+/// See [`AllocatorPool::snapshot`](crate::AllocatorPool::snapshot). With the
+/// `serde` feature this is `Serialize`, for emitting metrics.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct AllocSnapshot {
+    /// Elapsed wall-clock time of the process in milli-seconds.
+    pub elapsed_msecs: usize,
+    /// Current working set size (touched pages).
+    pub current_rss: usize,
+    /// Peak working set size (touched pages).
+    pub peak_rss: usize,
+    /// Current committed memory (backed by the page file).
+    pub current_commit: usize,
+    /// Peak committed memory (backed by the page file).
+    pub peak_commit: usize,
+    /// Count of hard page faults.
+    pub page_faults: usize,
+    /// Bytes in use by allocated blocks across the pool.
+    pub pool_used: usize,
+    /// Bytes committed across the pool.
+    pub pool_committed: usize,
+    /// Number of allocators in the pool.
+    pub heap_count: usize,
+}
+
+/// Return process information (time and memory usage).
+///
+/// The `current_rss` is precise on Windows and MacOSX; other systems estimate
+/// this using `current_commit`. The `commit` is precise on Windows but
+/// estimated on other systems as the amount of read/write accessible memory
+/// reserved by mimalloc.
+pub fn process_info() -> ProcessInfo {
+    let mut info = ProcessInfo::default();
+    unsafe {
+        mi_process_info(
+            &mut info.elapsed_msecs,
+            &mut info.user_msecs,
+            &mut info.system_msecs,
+            &mut info.current_rss,
+            &mut info.peak_rss,
+            &mut info.current_commit,
+            &mut info.peak_commit,
+            &mut info.page_faults,
+        );
+    }
+    info
+}
+
+/// The current working set size in bytes, the `current_rss` of
+/// [`process_info`].
+///
+/// Only queries this one value, e.g. for a gauge.
+pub fn current_rss_bytes() -> usize {
+    let mut rss = 0;
+    unsafe {
+        mi_process_info(
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut rss,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+        );
+    }
+    rss
+}
+
+/// The peak working set size in bytes, the `peak_rss` of [`process_info`].
+///
+/// Only queries this one value, e.g. for a gauge.
+pub fn peak_rss_bytes() -> usize {
+    let mut rss = 0;
+    unsafe {
+        mi_process_info(
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut rss,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+        );
+    }
+    rss
+}
+
+/// Capture the main statistics as text.
+///
+/// This is the output of [`mi_stats_print_out`] collected into a `String`
+/// instead of being written to `stderr`. Every call collects into its own
+/// buffer, so it's safe to call from multiple threads at once, and an output
+/// sink registered with [`set_output_sink`](crate::hooks::set_output_sink)
+/// doesn't see the dump.
+///
+/// Most detailed when using a debug build.
+pub fn capture_stats() -> String {
+    let mut out: Vec<u8> = Vec::new();
+    unsafe {
+        mi_stats_print_out(
+            Some(collect_output),
+            &mut out as *mut Vec<u8> as *mut c_void,
+        )
+    };
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Reset the statistics.
+///
+/// Useful to take clean before/after measurements around a region of code.
+///
+/// Note: This function is thread safe.
+pub fn reset() {
+    unsafe { mi_stats_reset() }
+}
+
+/// Merge the thread local statistics into the main statistics and reset them.
+///
+/// Note: This function is thread safe.
+pub fn merge() {
+    unsafe { mi_stats_merge() }
+}
+
+/// Output trampoline appending each message to the `Vec<u8>` passed as `arg`.
+unsafe extern "C" fn collect_output(msg: *const c_char, arg: *mut c_void) {
+    if msg.is_null() || arg.is_null() {
+        return;
+    }
+
+    let out = &mut *(arg as *mut Vec<u8>);
+    out.extend_from_slice(CStr::from_ptr(msg).to_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::Allocator;
+
+    #[test]
+    fn capture_stats_and_process_info() {
+        let alloc = Allocator::default();
+        let p = alloc.malloc(4096);
+        assert!(!p.is_null());
+
+        let text = capture_stats();
+        assert!(!text.is_empty());
+        assert!(process_info().peak_rss > 0);
+
+        alloc.free(p);
+    }
+
+    #[test]
+    fn capture_stats_bypasses_output_sink() {
+        use crate::hooks::set_output_sink;
+
+        if !crate::is_isolated() {
+            assert!(crate::run_isolated(
+                "stats::tests::capture_stats_bypasses_output_sink"
+            ));
+            return;
+        }
+
+        let sink = crate::SharedBuf::default();
+        set_output_sink(sink.clone());
+
+        let text = capture_stats();
+        assert!(text.contains("heap stats"));
+        assert!(sink.is_empty());
+    }
+
+    #[test]
+    fn peak_rss_watermark() {
+        let alloc = Allocator::default();
+        let p = alloc.malloc(16 << 20);
+        assert!(!p.is_null());
+        unsafe { p.write_bytes(1, 16 << 20) };
+
+        assert!(current_rss_bytes() > 0);
+        assert!(peak_rss_bytes() >= current_rss_bytes());
+
+        alloc.free(p);
+    }
+
+    #[test]
+    fn reset_then_allocate() {
+        const SIZE: usize = 32 << 20;
+
+        if !crate::is_isolated() {
+            assert!(crate::run_isolated("stats::tests::reset_then_allocate"));
+            return;
+        }
+
+        let alloc = Allocator::default();
+        alloc.free(alloc.malloc(SIZE));
+
+        // a huge block gets a segment of its own, tracked in release builds too
+        reset();
+        assert_eq!(crate::stat_columns(&capture_stats(), "segments")[1], 0.0);
+
+        let p = alloc.malloc(SIZE);
+        assert!(!p.is_null());
+        unsafe { p.write_bytes(1, SIZE) };
+        merge();
+        assert!(crate::stat_columns(&capture_stats(), "segments")[1] >= 1.0);
+
+        alloc.free(p);
+        reset();
+        assert_eq!(crate::stat_columns(&capture_stats(), "segments"), [0.0; 4]);
+    }
+}
@@ -0,0 +1,55 @@
+use std::marker::PhantomData;
+
+use cesium_libmimalloc_sys::allocator::{
+    mi_thread_done,
+    mi_thread_init,
+};
+
+/// Keeps mimalloc initialized on the current thread, see [`register_thread`].
+///
+/// The guard can't be sent to another thread, as it has to be dropped on the
+/// thread it was created on.
+pub struct ThreadGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for ThreadGuard {
+    fn drop(&mut self) {
+        unsafe { mi_thread_done() }
+    }
+}
+
+/// Initialize mimalloc on the current thread until the returned guard is
+/// dropped.
+///
+/// On most systems (pthreads, windows) this happens automatically, so this is
+/// only needed for threads created through raw OS APIs that bypass mimalloc's
+/// hooks. Dropping the guard calls `mi_thread_done`, which makes sure memory
+/// allocated on this thread but freed later by other threads is properly
+/// handled instead of leaking.
+pub fn register_thread() -> ThreadGuard {
+    unsafe { mi_thread_init() };
+    ThreadGuard {
+        _not_send: PhantomData,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::Allocator;
+
+    #[test]
+    fn guarded_thread() {
+        let handle = std::thread::spawn(|| {
+            let _guard = register_thread();
+            let alloc = Allocator::default();
+            let p = alloc.malloc(256);
+            assert!(!p.is_null());
+            unsafe { p.write_bytes(7, 256) };
+            alloc.free(p);
+        });
+
+        handle.join().unwrap();
+    }
+}
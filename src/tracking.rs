@@ -0,0 +1,121 @@
+use std::sync::atomic::{
+    AtomicU64,
+    Ordering,
+};
+
+use crate::allocator::Allocator;
+
+/// Counters of the calls made through a [`TrackingAllocator`].
+///
+/// See [`TrackingAllocator::stats`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrackingStats {
+    /// Number of calls to `malloc`.
+    pub allocs: u64,
+    /// Number of calls to `free`.
+    pub frees: u64,
+    /// Number of calls to `realloc`.
+    pub reallocs: u64,
+    /// Bytes requested by `malloc` and `realloc` calls.
+    pub bytes_requested: u64,
+}
+
+/// An [`Allocator`] which counts the calls made through it, for debugging
+/// allocation churn.
+///
+/// All the work is done by the inner allocator; the counters are only updated
+/// on the way in, whether or not the call succeeds.
+pub struct TrackingAllocator {
+    inner: Allocator,
+    allocs: AtomicU64,
+    frees: AtomicU64,
+    reallocs: AtomicU64,
+    bytes_requested: AtomicU64,
+}
+
+impl TrackingAllocator {
+    /// Count the calls made to `inner`.
+    pub fn new(inner: Allocator) -> Self {
+        TrackingAllocator {
+            inner,
+            allocs: AtomicU64::new(0),
+            frees: AtomicU64::new(0),
+            reallocs: AtomicU64::new(0),
+            bytes_requested: AtomicU64::new(0),
+        }
+    }
+
+    /// The allocator doing the work. Calls made directly on it aren't counted.
+    pub fn inner(&self) -> &Allocator {
+        &self.inner
+    }
+
+    /// Allocate `size` bytes, see [`Allocator::malloc`].
+    pub fn malloc(&self, size: usize) -> *mut u8 {
+        self.allocs.fetch_add(1, Ordering::Relaxed);
+        self.bytes_requested
+            .fetch_add(size as u64, Ordering::Relaxed);
+        self.inner.malloc(size)
+    }
+
+    /// Free `p`, see [`Allocator::free`].
+    pub fn free(&self, p: *mut u8) {
+        self.frees.fetch_add(1, Ordering::Relaxed);
+        self.inner.free(p)
+    }
+
+    /// Re-allocate `p` to `newsize` bytes, see [`Allocator::realloc`].
+    pub fn realloc(&self, p: *mut u8, newsize: usize) -> *mut u8 {
+        self.reallocs.fetch_add(1, Ordering::Relaxed);
+        self.bytes_requested
+            .fetch_add(newsize as u64, Ordering::Relaxed);
+        self.inner.realloc(p, newsize)
+    }
+
+    /// The counters so far.
+    ///
+    /// Each counter is read on its own, so calls made concurrently may be
+    /// reflected in some counters but not yet in others.
+    pub fn stats(&self) -> TrackingStats {
+        TrackingStats {
+            allocs: self.allocs.load(Ordering::Relaxed),
+            frees: self.frees.load(Ordering::Relaxed),
+            reallocs: self.reallocs.load(Ordering::Relaxed),
+            bytes_requested: self.bytes_requested.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cesium_libmimalloc_sys::heap::mi_heap_new;
+
+    use super::*;
+
+    #[test]
+    fn counts_calls() {
+        let tracking = TrackingAllocator::new(Allocator::new(1, unsafe { mi_heap_new() }));
+
+        let mut blocks: Vec<_> = (0..100).map(|_| tracking.malloc(32)).collect();
+        for p in blocks.drain(..50) {
+            tracking.free(p);
+        }
+        blocks[0] = tracking.realloc(blocks[0], 64);
+
+        assert_eq!(
+            tracking.stats(),
+            TrackingStats {
+                allocs: 100,
+                frees: 50,
+                reallocs: 1,
+                bytes_requested: 100 * 32 + 64,
+            }
+        );
+        assert_eq!(tracking.inner().live_block_count(), 50);
+
+        for p in blocks {
+            tracking.free(p);
+        }
+        assert_eq!(tracking.stats().frees, 100);
+    }
+}